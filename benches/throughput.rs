@@ -0,0 +1,61 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use mysterious_unnamed_rust_project::{parse_csv, ClientId, ClientRecords, Transaction};
+
+const TRANSACTION_COUNT: u32 = 100_000;
+const CLIENT_COUNT: u32 = 1_000;
+
+/// Mostly deposits, with a withdrawal every 10th transaction and a dispute of the
+/// previous deposit every 20th, spread across `CLIENT_COUNT` clients.
+fn synthetic_transactions() -> Vec<Transaction> {
+    (1..=TRANSACTION_COUNT)
+        .map(|tx_id| {
+            let client_id = (tx_id % CLIENT_COUNT) as ClientId;
+            if tx_id % 20 == 0 {
+                Transaction::new_dispute_txn(((tx_id - 1) % CLIENT_COUNT) as ClientId, tx_id - 1)
+            } else if tx_id % 10 == 0 {
+                Transaction::new_withdrawal_txn(client_id, tx_id, 1.5)
+            } else {
+                Transaction::new_deposit_txn(client_id, tx_id, 10.0)
+            }
+        })
+        .collect()
+}
+
+fn synthetic_csv(transactions: &[Transaction]) -> Vec<u8> {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for txn in transactions {
+        wtr.serialize(txn).unwrap();
+    }
+    wtr.into_inner().unwrap()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let csv = synthetic_csv(&synthetic_transactions());
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(TRANSACTION_COUNT as u64));
+    group.bench_function("parse_csv", |b| {
+        b.iter(|| parse_csv(black_box(csv.as_slice())).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_process(c: &mut Criterion) {
+    let transactions = synthetic_transactions();
+
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(TRANSACTION_COUNT as u64));
+    group.bench_function("process_transaction", |b| {
+        b.iter(|| {
+            let mut client_records = ClientRecords::new();
+            for txn in &transactions {
+                let _ = client_records.process_transaction(black_box(txn));
+            }
+            client_records
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_process);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+use std::{io::Write, sync::Arc};
+
+use anyhow::Context;
+use arrow::{
+    array::{ArrayRef, BooleanArray, Float32Array, PrimitiveArray},
+    datatypes::{ArrowPrimitiveType, DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use parquet::arrow::ArrowWriter;
+
+use crate::{ClientRecords, LedgerStore};
+
+/// The arrow type of the `client` column, matching `ClientId`.
+#[cfg(not(feature = "u32-client-ids"))]
+type ClientIdType = arrow::datatypes::UInt16Type;
+#[cfg(feature = "u32-client-ids")]
+type ClientIdType = arrow::datatypes::UInt32Type;
+
+impl<S: LedgerStore> ClientRecords<S> {
+    /// Writes every client record as a single Parquet row group with the columns
+    /// `client`, `available`, `held`, `total` and `locked`, ordered by client id.
+    pub fn export_parquet(&self, writer: impl Write + Send) -> anyhow::Result<()> {
+        let clients = self.sorted_clients();
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("client", ClientIdType::DATA_TYPE, false),
+            Field::new("available", DataType::Float32, false),
+            Field::new("held", DataType::Float32, false),
+            Field::new("total", DataType::Float32, false),
+            Field::new("locked", DataType::Boolean, false),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(PrimitiveArray::<ClientIdType>::from_iter_values(
+                clients.iter().map(|c| c.client_id),
+            )),
+            Arc::new(Float32Array::from_iter_values(
+                clients.iter().map(|c| c.available_amounts),
+            )),
+            Arc::new(Float32Array::from_iter_values(
+                clients.iter().map(|c| c.held_amounts),
+            )),
+            Arc::new(Float32Array::from_iter_values(
+                clients.iter().map(|c| c.total_amounts()),
+            )),
+            Arc::new(BooleanArray::from(
+                clients.iter().map(|c| c.is_locked()).collect::<Vec<_>>(),
+            )),
+        ];
+        let batch = RecordBatch::try_new(schema.clone(), columns)
+            .with_context(|| "failed to build client record batch")?;
+
+        let mut parquet_writer = ArrowWriter::try_new(writer, schema, None)
+            .with_context(|| "failed to create parquet writer")?;
+        parquet_writer
+            .write(&batch)
+            .with_context(|| "failed to write client records to parquet")?;
+        parquet_writer
+            .close()
+            .with_context(|| "failed to finalize parquet file")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+    use arrow::array::AsArray;
+    use arrow::datatypes::Float32Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_export_client_records_to_parquet() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(2, 2, 4.0),
+            Transaction::new_dispute_txn(2, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        let file = tempfile::tempfile().unwrap();
+        assert_that(&client_records.export_parquet(file.try_clone().unwrap())).is_ok();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+        let row_count: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_that!(row_count).is_equal_to(2);
+
+        let batch = &batches[0];
+        assert_that!(batch.column(0).as_primitive::<ClientIdType>().value(1)).is_equal_to(2);
+        assert_that!(batch.column(1).as_primitive::<Float32Type>().value(1)).is_equal_to(0.0);
+        assert_that!(batch.column(2).as_primitive::<Float32Type>().value(1)).is_equal_to(4.0);
+        assert_that!(batch.column(3).as_primitive::<Float32Type>().value(1)).is_equal_to(4.0);
+        assert_that!(batch.column(4).as_boolean().value(1)).is_equal_to(false);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    f64::consts::LN_2,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use crate::LedgerKey;
+
+/// How `ClientRecords` remembers which deposits and withdrawals it has applied,
+/// so that a transaction reusing one of their ids is rejected as a duplicate. The
+/// default `ExactDetector` never gets it wrong; a `BloomFilter` uses a fraction of
+/// the memory on enormous inputs but rejects a small share of new transactions.
+pub trait DuplicateDetector: fmt::Debug + Send + Sync {
+    /// Records that a deposit or withdrawal was applied under `key`.
+    fn insert(&mut self, key: LedgerKey);
+
+    /// Whether a deposit or withdrawal may have been applied under `key`.
+    fn contains(&self, key: LedgerKey) -> bool;
+
+    /// Forgets `key` after its transaction was reversed, so its id can be used
+    /// again. Detectors that cannot forget keep rejecting it.
+    fn remove(&mut self, _key: LedgerKey) {}
+
+    fn box_clone(&self) -> Box<dyn DuplicateDetector>;
+}
+
+impl Clone for Box<dyn DuplicateDetector> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Keeps every key in a hash set.
+#[derive(Debug, Clone, Default)]
+pub struct ExactDetector {
+    keys: HashSet<LedgerKey>,
+}
+
+impl DuplicateDetector for ExactDetector {
+    fn insert(&mut self, key: LedgerKey) {
+        self.keys.insert(key);
+    }
+
+    fn contains(&self, key: LedgerKey) -> bool {
+        self.keys.contains(&key)
+    }
+
+    fn remove(&mut self, key: LedgerKey) {
+        self.keys.remove(&key);
+    }
+
+    fn box_clone(&self) -> Box<dyn DuplicateDetector> {
+        Box::new(self.clone())
+    }
+}
+
+/// Keeps a fixed size bloom filter of the keys. It never misses a duplicate, but
+/// may report a new key as a duplicate, at a rate that stays below the one it was
+/// sized for as long as no more keys than expected are inserted. Keys cannot be
+/// removed, so the ids of reversed transactions stay in use.
+#[derive(Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter for up to `expected_keys` keys that reports a new key as a
+    /// duplicate with a probability of at most `false_positive_rate`.
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> BloomFilter {
+        let expected_keys = expected_keys.max(1) as f64;
+        let bit_count =
+            ((-expected_keys * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64).max(64);
+        let hash_count = ((bit_count as f64 / expected_keys * LN_2).round() as u32).max(1);
+
+        BloomFilter {
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+            hash_count,
+        }
+    }
+
+    /// The bit positions of `key`, derived from two hashes of it.
+    fn positions(&self, key: LedgerKey) -> impl Iterator<Item = u64> + '_ {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let first = hasher.finish();
+        // Double hashing: the second hash is the step between the positions.
+        hasher.write_u8(0xff);
+        let step = hasher.finish() | 1;
+
+        (0..u64::from(self.hash_count))
+            .map(move |i| first.wrapping_add(i.wrapping_mul(step)) % self.bit_count)
+    }
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BloomFilter")
+            .field("bit_count", &self.bit_count)
+            .field("hash_count", &self.hash_count)
+            .finish()
+    }
+}
+
+impl DuplicateDetector for BloomFilter {
+    fn insert(&mut self, key: LedgerKey) {
+        let positions: Vec<u64> = self.positions(key).collect();
+        for position in positions {
+            self.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, key: LedgerKey) -> bool {
+        self.positions(key)
+            .all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+
+    fn box_clone(&self) -> Box<dyn DuplicateDetector> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientId, ClientRecords, Transaction};
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_reject_the_same_duplicates_with_either_detector() {
+        let txns: Vec<Transaction> = (1..=1_000)
+            .flat_map(|tx_id| {
+                let deposit = Transaction::new_deposit_txn((tx_id % 7) as ClientId, tx_id, 1.0);
+                // Every tenth deposit is replayed later on.
+                let replay =
+                    (tx_id % 10 == 0).then(|| Transaction::new_deposit_txn(1, tx_id / 2, 1.0));
+                [Some(deposit), replay]
+            })
+            .flatten()
+            .collect();
+
+        let mut exact = ClientRecords::new();
+        let exact_rejected = exact.process_transactions(&txns).unwrap();
+        let mut bloom = ClientRecords::new();
+        bloom.set_duplicate_detector(BloomFilter::new(1_000, 0.0001));
+        let bloom_rejected = bloom.process_transactions(&txns).unwrap();
+
+        assert_that!(exact_rejected.len()).is_equal_to(100);
+        assert_that!(bloom_rejected).is_equal_to(exact_rejected);
+        assert_that!(bloom.view()).is_equal_to(exact.view());
+    }
+
+    #[test]
+    fn it_should_never_miss_a_key_in_a_bloom_filter() {
+        let mut filter = BloomFilter::new(500, 0.01);
+        for tx_id in 0..500 {
+            filter.insert((None, tx_id));
+        }
+
+        assert_that!((0..500).all(|tx_id| filter.contains((None, tx_id)))).is_true();
+        assert_that!(filter.contains((Some(1), 0))).is_false();
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::Write;
+
+use crate::{
+    Client, ClientRecords, LedgerKey, LedgerStore, ProcessTransactionError, Transaction,
+    TransactionType,
+};
+
+impl<S: LedgerStore> ClientRecords<S> {
+    /// Processes `txn` like `process_transaction`, and also describes what it did:
+    /// the client's balances and the referenced transaction's state before and
+    /// after, and the error if it was rejected.
+    pub fn explain_transaction(
+        &mut self,
+        txn: &Transaction,
+    ) -> (Result<(), ProcessTransactionError>, String) {
+        let key = self.ledger_key(txn.client_id, txn.tx_id);
+        let client_before = self.records.get(&txn.client_id).cloned();
+        let state_before = self.ledger_state(key);
+
+        let result = self.process_transaction(txn);
+
+        let mut explanation = format!(
+            "explain {} transaction: \"{}\" for client: {}",
+            txn.txn_type, txn.tx_id, txn.client_id
+        );
+        if let Some(amount) = txn.amount {
+            let _ = write!(explanation, " with amount: {:.4}", amount);
+        }
+        let _ = write!(
+            explanation,
+            "\n  client before: {}\n  client after: {}\n  tx state: {} -> {}\n  result: ",
+            describe_client(client_before.as_ref()),
+            describe_client(self.records.get(&txn.client_id)),
+            describe_state(state_before),
+            describe_state(self.ledger_state(key)),
+        );
+        match &result {
+            Ok(()) => explanation.push_str("applied"),
+            Err(err) => {
+                let _ = write!(explanation, "rejected: {}", err);
+            }
+        }
+
+        (result, explanation)
+    }
+
+    fn ledger_state(&self, key: LedgerKey) -> Option<TransactionType> {
+        self.ledger
+            .deposit(key)
+            .map(|deposit| deposit.status)
+            .or_else(|| {
+                self.ledger
+                    .withdrawal(key)
+                    .map(|withdrawal| withdrawal.status)
+            })
+    }
+}
+
+fn describe_client(client: Option<&Client>) -> String {
+    match client {
+        Some(client) => format!(
+            "available: {:.4}, held: {:.4}, total: {:.4}, locked: {}",
+            client.available_amounts,
+            client.held_amounts,
+            client.total_amounts(),
+            client.is_locked()
+        ),
+        None => "none".to_string(),
+    }
+}
+
+fn describe_state(state: Option<TransactionType>) -> String {
+    match state {
+        Some(state) => state.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_explain_the_effect_of_a_dispute() {
+        let mut client_records = ClientRecords::new();
+        client_records
+            .process_transaction(&Transaction::new_deposit_txn(1, 1, 10.0))
+            .unwrap();
+
+        let (result, explanation) =
+            client_records.explain_transaction(&Transaction::new_dispute_txn(1, 1));
+
+        assert_that(&result).is_ok();
+        assert_that!(explanation).is_equal_to(
+            "explain dispute transaction: \"1\" for client: 1\n  \
+             client before: available: 10.0000, held: 0.0000, total: 10.0000, locked: false\n  \
+             client after: available: 0.0000, held: 10.0000, total: 10.0000, locked: false\n  \
+             tx state: deposit -> dispute\n  \
+             result: applied"
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_explain_why_a_transaction_was_rejected() {
+        let mut client_records = ClientRecords::new();
+
+        let (result, explanation) =
+            client_records.explain_transaction(&Transaction::new_dispute_txn(1, 7));
+
+        assert_that(&result).is_err();
+        assert_that!(explanation).contains("client before: none\n  client after: none");
+        assert_that!(explanation).contains("tx state: none -> none");
+        assert_that!(explanation).contains("result: rejected: cannot $dispute transaction: \"7\"");
+    }
+}
@@ -0,0 +1,599 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use csv::{StringRecord, Trim};
+use flate2::read::GzDecoder;
+
+use crate::{ClientRecords, LedgerStore, Transaction};
+
+/// The columns of a line passed to `parse_line` or of a csv file without a header
+/// row, in order.
+const LINE_COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "event_id", "reference"];
+
+/// The quote and escape characters used by a CSV source, and whether it starts
+/// with a header row. The default matches the csv crate's defaults: fields are
+/// quoted with `"`, a quote inside a quoted field is escaped by doubling it, and
+/// the first row holds the headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CsvDialect {
+    pub quote: u8,
+    pub escape: Option<u8>,
+    /// Without a header row, the columns are read by position in the order
+    /// `type`, `client`, `tx`, `amount`, `event_id` and `reference`, as in
+    /// `parse_line`.
+    pub has_headers: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        CsvDialect {
+            quote: b'"',
+            escape: None,
+            has_headers: true,
+        }
+    }
+}
+
+/// Lazily parses transactions from a CSV source, one row at a time, so callers
+/// can process them as they are read instead of loading the whole file first.
+pub fn stream_csv(
+    csv: impl Read,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Transaction>>> {
+    CsvDialect::default().stream_csv(csv)
+}
+
+/// Streams the transactions of every `*.csv` and `*.csv.gz` file in `dir`, in
+/// lexicographic order of their file names, as one continuous sequence. A dispute
+/// in a later file can therefore reference a deposit from an earlier one. Each file
+/// needs its own header row.
+pub fn stream_csv_dir(
+    dir: &Path,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Transaction>>> {
+    CsvDialect::default().stream_csv_dir(dir)
+}
+
+/// Parses every transaction and sorts them by the integer value of `column`
+/// (e.g. a `seq` or epoch `timestamp` column), so that processing follows that
+/// order instead of the file order. Rows with the same value keep their file order.
+pub fn parse_csv_ordered_by(csv: impl Read, column: &str) -> anyhow::Result<Vec<Transaction>> {
+    CsvDialect::default().parse_csv_ordered_by(csv, column)
+}
+
+impl CsvDialect {
+    /// Like the free `stream_csv`, but reads the source with this dialect.
+    pub fn stream_csv(
+        self,
+        csv: impl Read,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Transaction>>> {
+        let (headers, records) = self.read_records(csv)?;
+        Ok(records.map(move |result| {
+            result
+                .and_then(|record| record.deserialize(Some(&headers)))
+                .with_context(|| "failed to parse transaction")
+        }))
+    }
+
+    /// Like the free `stream_csv_dir`, but reads every file with this dialect.
+    pub fn stream_csv_dir(
+        self,
+        dir: &Path,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Transaction>>> {
+        let mut paths = fs::read_dir(dir)
+            .with_context(|| format!("failed to read input directory: \"{}\"", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()
+            .with_context(|| format!("failed to read input directory: \"{}\"", dir.display()))?;
+        paths.retain(|path| {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            path.is_file() && (name.ends_with(".csv") || name.ends_with(".csv.gz"))
+        });
+        paths.sort();
+
+        Ok(paths.into_iter().flat_map(move |path| {
+            let transactions: Box<dyn Iterator<Item = anyhow::Result<Transaction>>> =
+                match self.open_csv_file(&path) {
+                    Ok(transactions) => Box::new(transactions.map(move |result| {
+                        result.with_context(|| format!("in file: \"{}\"", path.display()))
+                    })),
+                    Err(err) => Box::new(std::iter::once(Err(err))),
+                };
+            transactions
+        }))
+    }
+
+    /// Like the free `parse_csv_ordered_by`, but reads the source with this dialect.
+    pub fn parse_csv_ordered_by(
+        self,
+        csv: impl Read,
+        column: &str,
+    ) -> anyhow::Result<Vec<Transaction>> {
+        let (headers, records) = self.read_records(csv)?;
+        let column = column.to_lowercase();
+        let index = headers
+            .iter()
+            .position(|header| header == column)
+            .ok_or_else(|| anyhow!("missing column: \"{}\"", column))?;
+
+        let mut rows = records
+            .map(|result| {
+                let record = result?;
+                let order = record
+                    .get(index)
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid {} value", column))?;
+                Ok((order, record.deserialize(Some(&headers))?))
+            })
+            .collect::<anyhow::Result<Vec<(u64, Transaction)>>>()
+            .with_context(|| "failed to parse transaction")?;
+        rows.sort_by_key(|(order, _)| *order);
+
+        Ok(rows.into_iter().map(|(_, txn)| txn).collect())
+    }
+
+    fn open_csv_file(
+        self,
+        path: &Path,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Transaction>>> {
+        let file = BufReader::new(
+            File::open(path).with_context(|| format!("failed to open: \"{}\"", path.display()))?,
+        );
+        let reader: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        self.stream_csv(reader)
+            .with_context(|| format!("in file: \"{}\"", path.display()))
+    }
+
+    /// Reads the normalized headers and a lazy iterator over the normalized records.
+    fn read_records(
+        self,
+        csv: impl Read,
+    ) -> anyhow::Result<(
+        StringRecord,
+        impl Iterator<Item = csv::Result<StringRecord>>,
+    )> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(self.has_headers)
+            .trim(Trim::All)
+            .quote(self.quote)
+            .escape(self.escape)
+            .from_reader(csv);
+
+        let quote = char::from(self.quote);
+        let first = reader.headers().with_context(|| "failed to read headers")?;
+        let headers = if self.has_headers {
+            let headers = normalize_headers(first, quote)?;
+            reader.set_headers(headers.clone());
+            headers
+        } else {
+            // Without a header row, the csv reader hands out the first record here
+            // without consuming it, so its width decides how many columns there are.
+            if first.len() > LINE_COLUMNS.len() {
+                return Err(anyhow!(
+                    "rows have {} fields but at most {} are expected",
+                    first.len(),
+                    LINE_COLUMNS.len()
+                ));
+            }
+            StringRecord::from(&LINE_COLUMNS[..first.len()])
+        };
+
+        Ok((
+            headers,
+            reader
+                .into_records()
+                .map(move |result| result.map(|record| normalize_record(&record, quote))),
+        ))
+    }
+}
+
+/// The csv parser only treats a quote as quoting a field when it is the first
+/// character, so `deposit, "1"` leaves the quotes in place once the leading space
+/// is trimmed. Strip them (and any whitespace inside them) so that fields like
+/// `"deposit"` and `" 10.00 "` parse the same with or without surrounding spaces.
+fn normalize_field(field: &str, quote: char) -> &str {
+    let field = field.trim();
+    field
+        .strip_prefix(quote)
+        .and_then(|f| f.strip_suffix(quote))
+        .map_or(field, str::trim)
+}
+
+fn normalize_record(record: &StringRecord, quote: char) -> StringRecord {
+    let mut normalized: StringRecord = record
+        .iter()
+        .map(|field| normalize_field(field, quote))
+        .collect();
+    normalized.set_position(record.position().cloned());
+    normalized
+}
+
+pub fn parse_csv(csv: impl Read) -> anyhow::Result<Vec<Transaction>> {
+    stream_csv(csv)?.collect()
+}
+
+/// Parses a single csv line without a header row. The fields have to be in the
+/// order `type`, `client`, `tx`, `amount`, `event_id` and `reference`, and the
+/// trailing `amount`, `event_id` and `reference` fields may be left out. Fields
+/// are normalized the same way as in a csv file.
+pub fn parse_line(line: &str) -> anyhow::Result<Transaction> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(line.as_bytes());
+    let record = reader
+        .records()
+        .next()
+        .ok_or_else(|| anyhow!("line is empty"))?
+        .with_context(|| "failed to read line")?;
+    if record.len() > LINE_COLUMNS.len() {
+        return Err(anyhow!(
+            "line has {} fields but at most {} are expected",
+            record.len(),
+            LINE_COLUMNS.len()
+        ));
+    }
+
+    let headers = StringRecord::from(&LINE_COLUMNS[..record.len()]);
+    normalize_record(&record, '"')
+        .deserialize(Some(&headers))
+        .with_context(|| "failed to parse transaction")
+}
+
+impl<S: LedgerStore> ClientRecords<S> {
+    /// Parses a single csv line with `parse_line` and processes the transaction, for
+    /// sources that deliver one line at a time such as a socket or a message queue.
+    pub fn process_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let txn = parse_line(line)?;
+        self.process_transaction(&txn)?;
+
+        Ok(())
+    }
+}
+
+/// The format transactions are read in.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// A single JSON array of transaction objects.
+    Json,
+    /// One JSON transaction object per line.
+    Ndjson,
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            _ => Err(anyhow!("unknown input format: \"{}\"", s)),
+        }
+    }
+}
+
+/// Parses a JSON array of transaction objects with the same `type`, `client`, `tx`
+/// and `amount` fields as the csv columns. The whole array is read at once.
+pub fn parse_json(json: impl Read) -> anyhow::Result<Vec<Transaction>> {
+    serde_json::from_reader(json).with_context(|| "failed to parse transactions")
+}
+
+/// Lazily parses newline-delimited JSON, one transaction object per line, so
+/// callers can process them as they are read.
+pub fn stream_ndjson(
+    ndjson: impl Read,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Transaction>>> {
+    Ok(serde_json::Deserializer::from_reader(ndjson)
+        .into_iter::<Transaction>()
+        .map(|result| result.with_context(|| "failed to parse transaction")))
+}
+
+/// Lowercases the header names so that variations like `Type` or `TX` are
+/// matched, and rejects headers that name the same column more than once.
+fn normalize_headers(headers: &StringRecord, quote: char) -> anyhow::Result<StringRecord> {
+    let mut seen = HashSet::new();
+    let mut normalized = StringRecord::new();
+    for header in headers {
+        let header = normalize_field(header, quote).to_lowercase();
+        if !seen.insert(header.clone()) {
+            return Err(anyhow!("duplicate column: \"{}\"", header));
+        }
+        normalized.push_field(&header);
+    }
+
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransactionType;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_round_trip_transactions_through_csv() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.5
+withdrawal,1,2,0.25
+dispute,1,1,
+resolve,1,1,
+chargeback,2,3,
+";
+        let transactions = parse_csv(input.as_bytes()).unwrap();
+        assert_that!(transactions).has_length(5);
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        for txn in &transactions {
+            wtr.serialize(txn).unwrap();
+        }
+        let output = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+
+        assert_that!(output.as_str()).is_equal_to(input);
+    }
+
+    #[test]
+    fn it_should_match_headers_case_insensitively() {
+        let input = "\
+Type, Client, TX, Amount
+deposit, 1, 1, 10.0
+";
+        let transactions = parse_csv(input.as_bytes()).unwrap();
+
+        assert_that!(transactions).has_length(1);
+        assert_that!(transactions[0].txn_type).is_equal_to(TransactionType::Deposit);
+        assert_that!(transactions[0].client_id).is_equal_to(1);
+        assert_that!(transactions[0].tx_id).is_equal_to(1);
+        assert_that!(transactions[0].amount).is_equal_to(Some(10.0));
+    }
+
+    #[test]
+    fn it_should_reject_duplicate_columns() {
+        let input = "\
+type, client, tx, amount, Amount
+deposit, 1, 1, 10.0, 20.0
+";
+        assert_that!(parse_csv(input.as_bytes()))
+            .is_err()
+            .matches(|e| e.to_string() == "duplicate column: \"amount\"");
+    }
+
+    #[test]
+    fn it_should_parse_a_header_only_file_as_no_transactions() {
+        let transactions = parse_csv("type,client,tx,amount\n".as_bytes());
+
+        assert_that!(transactions).is_ok().is_empty();
+    }
+
+    #[test]
+    fn it_should_parse_a_completely_empty_file_as_no_transactions() {
+        let transactions = parse_csv("".as_bytes());
+
+        assert_that!(transactions).is_ok().is_empty();
+    }
+
+    #[test]
+    fn it_should_trim_quoted_fields() {
+        let transactions =
+            parse_csv(include_str!("../tests/fixtures/quoted.csv").as_bytes()).unwrap();
+
+        assert_that!(transactions).has_length(4);
+        assert_that!(transactions[0].txn_type).is_equal_to(TransactionType::Deposit);
+        assert_that!(transactions[0].client_id).is_equal_to(1);
+        assert_that!(transactions[0].amount).is_equal_to(Some(10.0));
+        assert_that!(transactions[1].txn_type).is_equal_to(TransactionType::Withdrawal);
+        assert_that!(transactions[1].amount).is_equal_to(Some(2.5));
+        assert_that!(transactions[2].txn_type).is_equal_to(TransactionType::Dispute);
+        assert_that!(transactions[2].amount).is_equal_to(None);
+        assert_that!(transactions[3].txn_type).is_equal_to(TransactionType::Resolve);
+        assert_that!(transactions[3].tx_id).is_equal_to(1);
+    }
+
+    #[test]
+    fn it_should_reject_a_quoted_amount_containing_a_comma() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,\"1,5\"
+";
+        assert_that!(parse_csv(input.as_bytes())).is_err();
+    }
+
+    #[test]
+    fn it_should_order_transactions_by_the_given_column() {
+        let input = "\
+seq,type,client,tx,amount
+4,resolve,1,1,
+2,dispute,1,1,
+1,deposit,1,1,10.0
+3,deposit,2,2,2.0
+";
+        let transactions = parse_csv_ordered_by(input.as_bytes(), "Seq").unwrap();
+        let tx_types: Vec<TransactionType> = transactions.iter().map(|t| t.txn_type).collect();
+        assert_that!(tx_types).is_equal_to(vec![
+            TransactionType::Deposit,
+            TransactionType::Dispute,
+            TransactionType::Deposit,
+            TransactionType::Resolve,
+        ]);
+
+        let (client_records, rejected) =
+            crate::ClientRecords::from_transactions(transactions).unwrap();
+        assert_that!(rejected).is_empty();
+        let client = client_records.view().get(&1).unwrap();
+        assert_that!(client.available_amounts).is_equal_to(10.0);
+        assert_that!(client.held_amounts).is_equal_to(0.0);
+
+        assert_that!(parse_csv_ordered_by(input.as_bytes(), "timestamp"))
+            .is_err()
+            .matches(|e| e.to_string() == "missing column: \"timestamp\"");
+    }
+
+    #[test]
+    fn it_should_reject_non_finite_amounts() {
+        for amount in ["inf", "-inf", "NaN"] {
+            let input = format!(
+                "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,{}\n",
+                amount
+            );
+            let result = parse_csv(input.as_bytes());
+            assert_that!(result).is_err().matches(|e| {
+                let message = format!("{:#}", e);
+                message.contains("is not a finite number") && message.contains("line: 3")
+            });
+        }
+    }
+
+    #[test]
+    fn it_should_parse_fields_with_a_custom_quote_and_escape() {
+        let input = "\
+type,client,tx,amount
+'deposit',1,1,' 10.5 '
+'with\\'drawal',1,2,1.0
+";
+        let dialect = CsvDialect {
+            quote: b'\'',
+            escape: Some(b'\\'),
+            ..Default::default()
+        };
+
+        let transactions = dialect
+            .stream_csv(input.as_bytes())
+            .unwrap()
+            .collect::<Vec<_>>();
+
+        assert_that!(transactions).has_length(2);
+        let deposit = transactions[0].as_ref().unwrap();
+        assert_that!(deposit.txn_type).is_equal_to(TransactionType::Deposit);
+        assert_that!(deposit.amount).is_equal_to(Some(10.5));
+        // The escaped quote is kept as part of the field, so it is not a valid type.
+        assert_that!(transactions[1]).is_err();
+    }
+
+    #[test]
+    fn it_should_parse_the_same_transactions_from_csv_json_and_ndjson() {
+        let csv = "\
+type,client,tx,amount
+deposit,1,1,10.5
+withdrawal,1,2,0.25
+dispute,1,1,
+";
+        let json = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": 10.5},
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": 0.25},
+            {"type": "dispute", "client": 1, "tx": 1, "amount": null}
+        ]"#;
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.5}
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": 0.25}
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+
+        let render = |transactions: Vec<Transaction>| {
+            let mut wtr = csv::Writer::from_writer(vec![]);
+            for txn in &transactions {
+                wtr.serialize(txn).unwrap();
+            }
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        let from_csv = render(parse_csv(csv.as_bytes()).unwrap());
+        let from_json = render(parse_json(json.as_bytes()).unwrap());
+        let from_ndjson = render(
+            stream_ndjson(ndjson.as_bytes())
+                .unwrap()
+                .collect::<anyhow::Result<Vec<Transaction>>>()
+                .unwrap(),
+        );
+
+        assert_that!(from_csv.as_str()).is_equal_to(csv);
+        assert_that!(from_json).is_equal_to(&from_csv);
+        assert_that!(from_ndjson).is_equal_to(&from_csv);
+    }
+
+    #[test]
+    fn it_should_reject_malformed_json_transactions() {
+        let ndjson =
+            "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.0}\nnot json\n";
+        let transactions: Vec<anyhow::Result<Transaction>> =
+            stream_ndjson(ndjson.as_bytes()).unwrap().collect();
+
+        assert_that!(transactions[0]).is_ok();
+        assert_that!(transactions[1]).is_err();
+        assert_that!(parse_json("[{\"type\": \"deposit\"}]".as_bytes())).is_err();
+    }
+
+    #[test]
+    fn it_should_process_raw_csv_lines() {
+        let mut client_records = ClientRecords::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "withdrawal,1,2,2.5",
+            "\"deposit\", 2, 3, \" 4.0 \"",
+            "dispute, 2, 3",
+            "deposit,1,4,1.0,evt-4",
+        ] {
+            assert_that(&client_records.process_line(line)).is_ok();
+        }
+
+        let client = &client_records.view()[&1];
+        assert_that!(client.available_amounts).is_equal_to(8.5);
+        let client = &client_records.view()[&2];
+        assert_that!(client.held_amounts).is_equal_to(4.0);
+
+        assert_that!(parse_line("deposit,1,4,1.0,evt-4").unwrap().event_id)
+            .is_equal_to(Some("evt-4".to_string()));
+        assert_that(&client_records.process_line("")).is_err();
+        assert_that(&client_records.process_line("deposit,1")).is_err();
+        assert_that(&client_records.process_line("deposit,1,5,1.0,evt,ref,extra")).is_err();
+        assert_that(&client_records.process_line("deposit,1,1,10.0")).is_err();
+    }
+
+    #[test]
+    fn it_should_parse_a_reference_as_the_sixth_field_of_a_line() {
+        let txn = parse_line("deposit,1,1,10.0,,inv-1").unwrap();
+        assert_that!(txn.event_id).is_none();
+        assert_that!(txn.reference).is_equal_to(Some("inv-1".to_string()));
+
+        let mut client_records = ClientRecords::new();
+        assert_that(&client_records.process_line("deposit,1,1,10.0,evt-1,inv-1")).is_ok();
+        assert_that!(client_records.view()[&1].last_reference()).is_equal_to(Some("inv-1"));
+    }
+
+    #[test]
+    fn it_should_read_columns_by_position_without_a_header_row() {
+        let input = "deposit,1,1,10.5\nwithdrawal,1,2,0.25\ndispute,1,1,\n";
+        let dialect = CsvDialect {
+            has_headers: false,
+            ..Default::default()
+        };
+
+        let transactions = dialect
+            .stream_csv(input.as_bytes())
+            .unwrap()
+            .collect::<anyhow::Result<Vec<Transaction>>>()
+            .unwrap();
+
+        assert_that!(transactions).has_length(3);
+        assert_that!(transactions[0].txn_type).is_equal_to(TransactionType::Deposit);
+        assert_that!(transactions[0].amount).is_equal_to(Some(10.5));
+        assert_that!(transactions[1].tx_id).is_equal_to(2);
+        assert_that!(transactions[2].amount).is_none();
+        // With the default dialect, the first row is taken as the header row.
+        assert_that!(parse_csv(input.as_bytes())).is_err();
+    }
+}
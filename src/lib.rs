@@ -1,14 +1,39 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
 
 use anyhow::anyhow;
-use serde::{ser::SerializeStruct, Serialize};
+use serde::Serialize;
 use thiserror::Error;
 
+#[cfg(feature = "parquet")]
+mod columnar;
+mod dedup;
+mod explain;
+mod input;
+mod output;
+mod progress;
+mod report;
+mod reverse;
+mod sequence;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
 mod tx;
 
+pub use dedup::*;
+pub use input::*;
+pub use output::*;
+pub use progress::*;
+pub use report::*;
+pub use sequence::*;
+pub use store::*;
 pub use tx::*;
 
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Debug, PartialEq, Clone)]
 pub enum ProcessTransactionError {
     #[error("transaction: \"{0}\" has already been processed")]
     DuplicateTransaction(u32),
@@ -17,19 +42,186 @@ pub enum ProcessTransactionError {
     #[error(
         "cannot ${2} transaction: \"{0}\" with client id: \"{1}\". no deposit with this id exists"
     )]
-    MissingTransaction(u32, u16, TransactionType),
-    #[error("${1} transaction: \"{0}\" failed. last transaction state was: {2}")]
+    MissingTransaction(u32, ClientId, TransactionType),
+    #[error(
+        "${1} transaction: \"{0}\" failed. last transaction state was: {2}{}",
+        if is_missing_dispute(*.1, *.2) { ". the deposit has no open dispute" } else { "" }
+    )]
     InvalidTransactionState(u32, TransactionType, TransactionType),
     #[error("${1} transaction: \"{0}\" failed. client account: {1} is frozen.")]
-    ClientAccountFrozen(u32, TransactionType, u16),
+    ClientAccountFrozen(u32, TransactionType, ClientId),
+    #[error(
+        "cannot dispute transaction: \"{0}\". it is a withdrawal and only deposits can be disputed"
+    )]
+    CannotDisputeWithdrawal(u32),
+    #[error("dispute transaction: \"{0}\" failed. it would push the held funds of client: {1} above the limit")]
+    HeldLimitExceeded(u32, ClientId),
+    #[error("transaction: \"{0}\" failed. amount: {1} is below the minimum transaction amount")]
+    AmountTooSmall(u32, f32),
+    #[error(
+        "transaction: \"{0}\" failed. the client's held funds do not cover the disputed amount"
+    )]
+    HeldFundsInconsistent(u32),
+    #[error("adjustment failed. the {1} funds of client: {0} do not cover the amount")]
+    AdjustmentExceedsBalance(ClientId, Bucket),
+    #[error("correction failed. it would leave the total of client: {0} below zero")]
+    NegativeCorrection(ClientId),
+    #[error("${1} transaction: \"{0}\" failed. amount is zero")]
+    ZeroAmount(u32, TransactionType),
+    #[error("sequence numbers are exhausted")]
+    SequenceExhausted,
+    #[error("${1} transaction: \"{0}\" failed. client: {2} has already been finalized")]
+    ClientFinalized(u32, TransactionType, ClientId),
+    #[error("dispute transaction: \"{0}\" failed. amount: {1} is not part of the deposit")]
+    InvalidDisputeAmount(u32, f32),
+    #[error("${1} transaction: \"{0}\" failed. event id: \"{2}\" was already used by another transaction")]
+    EventIdConflict(u32, TransactionType, String),
+    #[error("${1} transaction: \"{0}\" failed. the withdrawal is not pending")]
+    WithdrawalNotPending(u32, TransactionType),
+    #[error("transaction: \"{0}\" would leave the total of client: {1} below zero")]
+    NegativeBalance(u32, ClientId),
+    #[error("${1} transaction: \"{0}\" cannot be reversed")]
+    IrreversibleTransaction(u32, TransactionType),
+    #[error("${1} transaction: \"{0}\" failed. internal error: {2}")]
+    Internal(u32, TransactionType, &'static str),
+}
+
+impl ProcessTransactionError {
+    /// Whether processing can carry on after this error by skipping the offending
+    /// transaction. Unrecoverable errors mean an internal invariant is broken, so
+    /// any further output could not be trusted.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            ProcessTransactionError::HeldFundsInconsistent(_)
+            | ProcessTransactionError::SequenceExhausted
+            | ProcessTransactionError::NegativeBalance(..)
+            | ProcessTransactionError::Internal(..) => false,
+            ProcessTransactionError::DuplicateTransaction(_)
+            | ProcessTransactionError::InsufficientFunds(..)
+            | ProcessTransactionError::MissingTransaction(..)
+            | ProcessTransactionError::InvalidTransactionState(..)
+            | ProcessTransactionError::ClientAccountFrozen(..)
+            | ProcessTransactionError::CannotDisputeWithdrawal(_)
+            | ProcessTransactionError::HeldLimitExceeded(..)
+            | ProcessTransactionError::AmountTooSmall(..)
+            | ProcessTransactionError::AdjustmentExceedsBalance(..)
+            | ProcessTransactionError::NegativeCorrection(_)
+            | ProcessTransactionError::ZeroAmount(..)
+            | ProcessTransactionError::ClientFinalized(..)
+            | ProcessTransactionError::InvalidDisputeAmount(..)
+            | ProcessTransactionError::EventIdConflict(..)
+            | ProcessTransactionError::WithdrawalNotPending(..)
+            | ProcessTransactionError::IrreversibleTransaction(..) => true,
+        }
+    }
+
+    /// A short name for the kind of error, without the details of the transaction,
+    /// for grouping errors in reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProcessTransactionError::DuplicateTransaction(_) => "duplicate transaction",
+            ProcessTransactionError::InsufficientFunds(..) => "insufficient funds",
+            ProcessTransactionError::MissingTransaction(..) => "missing transaction",
+            ProcessTransactionError::InvalidTransactionState(..) => "invalid transaction state",
+            ProcessTransactionError::ClientAccountFrozen(..) => "frozen account",
+            ProcessTransactionError::CannotDisputeWithdrawal(_) => "disputed withdrawal",
+            ProcessTransactionError::HeldLimitExceeded(..) => "held limit exceeded",
+            ProcessTransactionError::AmountTooSmall(..) => "amount too small",
+            ProcessTransactionError::HeldFundsInconsistent(_) => "inconsistent held funds",
+            ProcessTransactionError::AdjustmentExceedsBalance(..) => "adjustment exceeds balance",
+            ProcessTransactionError::NegativeCorrection(_) => "negative correction",
+            ProcessTransactionError::ZeroAmount(..) => "zero amount",
+            ProcessTransactionError::SequenceExhausted => "sequence exhausted",
+            ProcessTransactionError::ClientFinalized(..) => "finalized client",
+            ProcessTransactionError::InvalidDisputeAmount(..) => "invalid dispute amount",
+            ProcessTransactionError::EventIdConflict(..) => "event id conflict",
+            ProcessTransactionError::WithdrawalNotPending(..) => "withdrawal not pending",
+            ProcessTransactionError::NegativeBalance(..) => "negative balance",
+            ProcessTransactionError::IrreversibleTransaction(..) => "irreversible transaction",
+            ProcessTransactionError::Internal(..) => "internal error",
+        }
+    }
+}
+
+/// Whether a `txn_type` that settles a dispute was applied to a deposit in
+/// `state`, which has no open dispute to settle. This commonly happens when the
+/// input delivers a resolve or chargeback before its dispute.
+fn is_missing_dispute(txn_type: TransactionType, state: TransactionType) -> bool {
+    matches!(
+        txn_type,
+        TransactionType::Resolve | TransactionType::Chargeback
+    ) && matches!(state, TransactionType::Deposit | TransactionType::Resolve)
+}
+
+/// One of the two balances a client's funds are split between.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Bucket {
+    Available,
+    Held,
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bucket::Available => write!(f, "available"),
+            Bucket::Held => write!(f, "held"),
+        }
+    }
+}
+
+/// Why a balance operation on a `Client` was refused. The caller knows which
+/// transaction asked for it and turns this into a `ProcessTransactionError`.
+#[derive(Error, Debug, PartialEq, Clone, Copy)]
+pub enum BalanceError {
+    #[error("the available funds do not cover the amount")]
+    InsufficientAvailable,
+    #[error("the held funds do not cover the amount")]
+    InsufficientHeld,
+    #[error("the total would go below zero")]
+    NegativeTotal,
+}
+
+impl BalanceError {
+    fn for_transaction(self, txn: &Transaction) -> ProcessTransactionError {
+        match self {
+            BalanceError::InsufficientAvailable => {
+                ProcessTransactionError::InsufficientFunds(txn.tx_id, txn.txn_type)
+            }
+            // Held funds should always cover an open dispute. If they don't,
+            // releasing them would break `total = available + held`.
+            BalanceError::InsufficientHeld => {
+                ProcessTransactionError::HeldFundsInconsistent(txn.tx_id)
+            }
+            BalanceError::NegativeTotal => {
+                ProcessTransactionError::NegativeBalance(txn.tx_id, txn.client_id)
+            }
+        }
+    }
+}
+
+/// Why a client's account was locked.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LockReason {
+    /// One of the client's deposits was charged back.
+    Chargeback,
+    /// One of the client's withdrawals was charged back.
+    WithdrawalChargeback,
+    /// The client was read back from a client table, which only records that the
+    /// account is locked.
+    Unknown,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Client {
-    client_id: u16,
+    client_id: ClientId,
     available_amounts: f32,
     held_amounts: f32,
-    is_locked: bool,
+    /// Funds of withdrawals that are waiting to be settled or cancelled.
+    pending_amounts: f32,
+    lock_reason: Option<LockReason>,
+    had_errors: bool,
+    /// The reference of the last applied transaction that carried one.
+    last_reference: Option<String>,
 }
 
 impl Serialize for Client {
@@ -37,534 +229,3344 @@ impl Serialize for Client {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("Client", 5)?;
-        state.serialize_field("client", &self.client_id)?;
-        state.serialize_field("available", &format!("{:.4}", &self.available_amounts))?;
-        state.serialize_field("held", &format!("{:.4}", &self.held_amounts))?;
-        state.serialize_field("total", &format!("{:.4}", &self.total_amounts()))?;
-        state.serialize_field("locked", &self.is_locked)?;
-        state.end()
+        FormattedClient::new(self, &OutputFormat::default()).serialize(serializer)
     }
 }
 
 impl Client {
-    pub fn new(client_id: u16) -> Client {
-        return Client {
+    pub fn new(client_id: ClientId) -> Client {
+        Client {
             client_id,
             available_amounts: 0.0,
             held_amounts: 0.0,
-            is_locked: false,
-        };
+            pending_amounts: 0.0,
+            lock_reason: None,
+            had_errors: false,
+            last_reference: None,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock_reason.is_some()
+    }
+
+    /// Why the account is locked, or `None` if it is not.
+    pub fn lock_reason(&self) -> Option<LockReason> {
+        self.lock_reason
+    }
+
+    /// The reference of the last applied transaction for this client that carried
+    /// one, or `None` if none did.
+    pub fn last_reference(&self) -> Option<&str> {
+        self.last_reference.as_deref()
+    }
+
+    /// The fraction of the client's total that is held, or zero if the total is zero.
+    pub fn held_ratio(&self) -> f32 {
+        let total = self.total_amounts();
+        if total == 0.0 {
+            0.0
+        } else {
+            self.held_amounts / total
+        }
+    }
+
+    /// Credits `amount` to the available funds.
+    pub fn apply_deposit(&mut self, amount: f32) {
+        self.available_amounts += amount;
+    }
+
+    /// Draws `amount` from the available funds, which may go as far as `overdraft`
+    /// below zero. Held funds are contested, so a withdrawal is only ever checked
+    /// against and drawn from the available funds, even if the total would cover it.
+    pub fn apply_withdrawal(
+        &mut self,
+        amount: f32,
+        overdraft: f32,
+        epsilon: f32,
+    ) -> Result<(), BalanceError> {
+        if !covers(self.available_amounts + overdraft, amount, epsilon) {
+            return Err(BalanceError::InsufficientAvailable);
+        }
+
+        self.available_amounts = (self.available_amounts - amount).max(-overdraft);
+        Ok(())
+    }
+
+    /// Takes a deposit's `amount` back out of the available funds, which go negative
+    /// if they no longer cover it.
+    pub fn reverse_deposit(&mut self, amount: f32) {
+        self.available_amounts -= amount;
+    }
+
+    /// Moves `amount` from the available to the held funds.
+    pub fn hold(&mut self, amount: f32, epsilon: f32) -> Result<(), BalanceError> {
+        if !covers(self.available_amounts, amount, epsilon) {
+            return Err(BalanceError::InsufficientAvailable);
+        }
+
+        self.available_amounts = (self.available_amounts - amount).max(0.0);
+        self.held_amounts += amount;
+        Ok(())
+    }
+
+    /// Moves `amount` from the held back to the available funds.
+    pub fn release(&mut self, amount: f32, epsilon: f32) -> Result<(), BalanceError> {
+        if !covers(self.held_amounts, amount, epsilon) {
+            return Err(BalanceError::InsufficientHeld);
+        }
+
+        self.held_amounts = (self.held_amounts - amount).max(0.0);
+        self.available_amounts += amount;
+        Ok(())
+    }
+
+    /// Removes `amount` from the held funds and locks the account.
+    pub fn charge_back(&mut self, amount: f32, epsilon: f32) -> Result<(), BalanceError> {
+        if !covers(self.held_amounts, amount, epsilon) {
+            return Err(BalanceError::InsufficientHeld);
+        }
+
+        self.held_amounts = (self.held_amounts - amount).max(0.0);
+        self.lock_reason = Some(LockReason::Chargeback);
+        Ok(())
+    }
+
+    /// Applies `change`, which takes funds out of the account. With `strict_balance`
+    /// it is applied to a copy first, and the client is left unchanged if that
+    /// leaves the total below zero.
+    fn apply_checked(
+        &mut self,
+        strict_balance: bool,
+        epsilon: f32,
+        change: impl FnOnce(&mut Client) -> Result<(), BalanceError>,
+    ) -> Result<(), BalanceError> {
+        if !strict_balance {
+            return change(self);
+        }
+
+        let mut staged = self.clone();
+        change(&mut staged)?;
+        if staged.total_amounts() + epsilon < 0.0 {
+            return Err(BalanceError::NegativeTotal);
+        }
+        *self = staged;
+        Ok(())
+    }
+
+    /// Compares two clients, treating balances within `epsilon` of each other as
+    /// equal. Unlike `==`, this tolerates float error from different but equivalent
+    /// sequences of transactions.
+    pub fn approx_eq(&self, other: &Client, epsilon: f32) -> bool {
+        self.client_id == other.client_id
+            && (self.available_amounts - other.available_amounts).abs() <= epsilon
+            && (self.held_amounts - other.held_amounts).abs() <= epsilon
+            && (self.pending_amounts - other.pending_amounts).abs() <= epsilon
+            && self.is_locked() == other.is_locked()
+            && self.had_errors == other.had_errors
+            && self.last_reference == other.last_reference
+    }
+
+    /// Builds a client in a given state directly, without applying transactions.
+    #[cfg(test)]
+    pub(crate) fn new_with_balances(
+        client_id: ClientId,
+        available_amounts: f32,
+        held_amounts: f32,
+        is_locked: bool,
+    ) -> Client {
+        Client {
+            client_id,
+            available_amounts,
+            held_amounts,
+            pending_amounts: 0.0,
+            lock_reason: is_locked.then_some(LockReason::Unknown),
+            had_errors: false,
+            last_reference: None,
+        }
     }
 
+    /// Pending withdrawals still count towards the total until they are settled.
     fn total_amounts(&self) -> f32 {
-        return self.held_amounts + self.available_amounts;
+        self.held_amounts + self.available_amounts + self.pending_amounts
+    }
+
+    fn balance_mut(&mut self, bucket: Bucket) -> &mut f32 {
+        match bucket {
+            Bucket::Available => &mut self.available_amounts,
+            Bucket::Held => &mut self.held_amounts,
+        }
+    }
+}
+
+/// How a client's balances changed between two snapshots of the records.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientDelta {
+    pub client_id: ClientId,
+    pub d_available: f32,
+    pub d_held: f32,
+    pub d_total: f32,
+    pub locked_changed: bool,
+}
+
+/// A manual correction recorded in the corrections log.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Correction {
+    /// Position of the correction in the log, starting at 1.
+    pub sequence: u64,
+    pub client_id: ClientId,
+    pub delta_available: f32,
+    pub delta_held: f32,
+    pub reason: String,
+}
+
+/// Which funds a dispute has to be covered by. It makes a difference once a
+/// deposit has been withdrawn and the balance refilled by a later deposit: after
+/// depositing 10, withdrawing 10 and depositing 10 again, the available funds
+/// cover a dispute of the first deposit, but the funds of that deposit are gone.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum DisputePolicy {
+    /// The dispute only has to be covered by the client's current available
+    /// funds, wherever they came from.
+    #[default]
+    CurrentAvailable,
+    /// The disputed funds also have to still be left over from the deposit
+    /// itself. Withdrawals draw from the client's oldest deposits first, skipping
+    /// funds that a void, chargeback or reversal took back out.
+    OriginalFunds,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "current-available" => Ok(DisputePolicy::CurrentAvailable),
+            "original-funds" => Ok(DisputePolicy::OriginalFunds),
+            _ => Err(anyhow!("unknown dispute policy: \"{}\"", s)),
+        }
+    }
+}
+
+/// How much a client has deposited and withdrawn over its lifetime, which tells
+/// how much of each deposit withdrawals have drawn from.
+#[derive(Debug, Default, Clone)]
+struct FundsFlow {
+    deposited: f32,
+    withdrawn: f32,
+    /// Funds taken back out of deposits by voids, chargebacks and reversals, as the
+    /// deposit's `deposited_before` and the amount taken. They are no longer there
+    /// for withdrawals to draw from.
+    removed: Vec<(f32, f32)>,
+}
+
+impl FundsFlow {
+    /// How much is left of a deposit of `amount` that was made once
+    /// `deposited_before` had been deposited, after withdrawals drew from the
+    /// deposits still in the account, oldest first.
+    fn left_over(&self, deposited_before: f32, amount: f32) -> f32 {
+        let removed_before: f32 = self
+            .removed
+            .iter()
+            .filter(|(removed_from, _)| *removed_from < deposited_before)
+            .map(|(_, removed)| removed)
+            .sum();
+
+        (deposited_before - removed_before + amount - self.withdrawn).clamp(0.0, amount)
+    }
+}
+
+/// A saved copy of the client balances and transaction ledger that a
+/// `ClientRecords` can later be rolled back to.
+#[derive(Debug, Clone)]
+pub struct Checkpoint<S = MemoryStore>(ClientRecords<S>);
+
+/// The client balances and the ledger of the transactions that produced them. The
+/// ledger of deposits and withdrawals is kept in a `LedgerStore`, which is a
+/// `MemoryStore` unless created with `ClientRecords::with_store`. The client
+/// balances are always kept in memory.
+#[derive(Debug, Clone)]
+pub struct ClientRecords<S = MemoryStore> {
+    records: HashMap<ClientId, Client>,
+    ledger: S,
+    duplicates: Box<dyn DuplicateDetector>,
+    client_scoped_tx_ids: bool,
+    max_held: Option<f32>,
+    overdraft_limit: Option<f32>,
+    epsilon: f32,
+    lock_freezes_all: bool,
+    prune_finalized: bool,
+    min_transaction_amount: Option<f32>,
+    seal_on_chargeback: bool,
+    allow_negative_corrections: bool,
+    corrections: Vec<Correction>,
+    correction_sequence: Sequence,
+    strict: bool,
+    withdrawal_disputes: bool,
+    completed_clients: HashSet<ClientId>,
+    pending_flush: Vec<ClientId>,
+    dead_letters: Vec<Transaction>,
+    /// The transaction each applied event id belongs to.
+    applied_events: HashMap<String, (TransactionType, ClientId, u32)>,
+    ignore_missing_disputes: bool,
+    dedupe_across_files: bool,
+    pending_withdrawals: bool,
+    strict_balance: bool,
+    dispute_policy: DisputePolicy,
+    flows: HashMap<ClientId, FundsFlow>,
+}
+
+impl Default for ClientRecords {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Collects transactions into client records, processing them in order.
+///
+/// Rejected transactions are silently ignored, the same way `main` ignores them.
+/// Use `ClientRecords::from_transactions` to find out which ones were rejected.
+impl FromIterator<Transaction> for ClientRecords {
+    fn from_iter<I: IntoIterator<Item = Transaction>>(txns: I) -> Self {
+        let mut client_records = ClientRecords::new();
+        for txn in txns {
+            let _ = client_records.process_transaction(&txn);
+        }
+        client_records
     }
 }
 
-#[derive(Debug)]
-pub struct ClientRecords {
-    records: HashMap<u16, Client>,
-    deposits: HashMap<u32, Deposit>,
-    withdrawals: HashSet<u32>,
+/// The default tolerance for balance comparisons: half of the smallest amount that
+/// shows up in the output, so float error is never visible as a rejection.
+pub const DEFAULT_EPSILON: f32 = 0.00005;
+
+/// Whether `balance` is enough to cover `amount`, allowing for float error of up
+/// to `epsilon`.
+fn covers(balance: f32, amount: f32, epsilon: f32) -> bool {
+    balance + epsilon >= amount
 }
 
 impl ClientRecords {
     pub fn new() -> ClientRecords {
-        return ClientRecords {
+        ClientRecords::with_store(MemoryStore::default())
+    }
+
+    /// Creates empty client records pre-sized for `clients` clients and a ledger of
+    /// `transactions` transactions, so that large batches do not rehash as they
+    /// are processed.
+    pub fn with_capacity(clients: usize, transactions: usize) -> ClientRecords {
+        let mut client_records =
+            ClientRecords::with_store(MemoryStore::with_capacity(transactions));
+        client_records.records.reserve(clients);
+        client_records
+    }
+
+    /// Builds client records from a sequence of transactions without touching any
+    /// files or stdout. Rejected transactions are collected alongside their tx id
+    /// instead of stopping the build. Only fatal errors (i.e. a broken internal
+    /// invariant) are returned as `Err`.
+    pub fn from_transactions(
+        txns: impl IntoIterator<Item = Transaction>,
+    ) -> anyhow::Result<(ClientRecords, Vec<(u32, ProcessTransactionError)>)> {
+        let mut client_records = ClientRecords::new();
+        let rejected = client_records.process_transactions(txns)?;
+
+        Ok((client_records, rejected))
+    }
+}
+
+impl<S: LedgerStore> ClientRecords<S> {
+    /// Creates empty client records that keep their ledger in `ledger`.
+    pub fn with_store(ledger: S) -> ClientRecords<S> {
+        ClientRecords {
             records: HashMap::new(),
-            deposits: HashMap::new(),
-            withdrawals: HashSet::new(),
-        };
+            ledger,
+            duplicates: Box::new(ExactDetector::default()),
+            client_scoped_tx_ids: false,
+            max_held: None,
+            overdraft_limit: None,
+            epsilon: DEFAULT_EPSILON,
+            lock_freezes_all: false,
+            prune_finalized: false,
+            min_transaction_amount: None,
+            seal_on_chargeback: false,
+            allow_negative_corrections: false,
+            corrections: vec![],
+            correction_sequence: Sequence::new(),
+            strict: false,
+            withdrawal_disputes: false,
+            completed_clients: HashSet::new(),
+            pending_flush: vec![],
+            dead_letters: vec![],
+            applied_events: HashMap::new(),
+            ignore_missing_disputes: false,
+            dedupe_across_files: false,
+            pending_withdrawals: false,
+            strict_balance: false,
+            dispute_policy: DisputePolicy::default(),
+            flows: HashMap::new(),
+        }
     }
 
-    pub fn view(&self) -> &HashMap<u16, Client> {
-        return &self.records;
+    /// Rejects deposits and withdrawals for less than `min_amount`. Disputes,
+    /// resolves and chargebacks are exempt since they carry no amount of their own.
+    pub fn set_min_transaction_amount(&mut self, min_amount: f32) {
+        self.min_transaction_amount = Some(min_amount);
     }
 
-    fn is_txn_processed(&self, id: u32) -> bool {
-        return self.deposits.contains_key(&id) || self.withdrawals.contains(&id);
+    /// When enabled, deposits that can no longer be disputed (i.e. charged back or
+    /// voided) are dropped from the ledger to save memory on long runs. Only
+    /// their tx id is kept for duplicate detection, so a later dispute of one is
+    /// reported as a missing transaction rather than as being in an invalid state.
+    pub fn set_prune_finalized(&mut self, enabled: bool) {
+        self.prune_finalized = enabled;
     }
 
-    pub fn process_transaction(&mut self, txn: &Transaction) -> anyhow::Result<()> {
-        let is_txn_processed = self.is_txn_processed(txn.tx_id);
-        let record = self
-            .records
-            .entry(txn.client_id)
-            .or_insert_with(|| Client::new(txn.client_id));
-        let amount = txn.amount.unwrap_or(0.0);
+    /// When enabled, an account that has been charged back is sealed: every
+    /// transaction for it, deposits included, is rejected as frozen until an
+    /// `unlock` transaction reactivates it.
+    pub fn set_seal_on_chargeback(&mut self, enabled: bool) {
+        self.seal_on_chargeback = enabled;
+    }
 
-        match txn.txn_type {
-            TransactionType::Deposit => {
-                if is_txn_processed {
-                    return Err(anyhow!(ProcessTransactionError::DuplicateTransaction(
-                        txn.tx_id
-                    )));
-                }
+    /// Allows withdrawals to be disputed. A disputed withdrawal that is charged back
+    /// is reversed, crediting its amount back to available and locking the account,
+    /// while resolving it leaves the balances untouched. Withdrawals cannot be voided.
+    pub fn set_withdrawal_disputes(&mut self, enabled: bool) {
+        self.withdrawal_disputes = enabled;
+    }
 
-                record.available_amounts += amount;
-                self.deposits.insert(
-                    txn.tx_id,
-                    Deposit {
-                        client_id: txn.client_id,
-                        amount,
-                        status: TransactionType::Deposit,
-                    },
-                );
-            }
-            TransactionType::Withdrawal => {
-                if is_txn_processed {
-                    return Err(anyhow!(ProcessTransactionError::DuplicateTransaction(
-                        txn.tx_id
-                    )));
-                }
+    /// Strict mode rejects transactions that are technically valid but most likely
+    /// a data error, such as a deposit or withdrawal of zero, instead of applying
+    /// them as no-ops.
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.strict = enabled;
+    }
 
-                if record.is_locked {
-                    return Err(anyhow!(ProcessTransactionError::ClientAccountFrozen(
-                        txn.tx_id,
-                        txn.txn_type,
-                        txn.client_id
-                    )));
-                }
+    /// Allows `apply_correction` to leave a client's total below zero.
+    pub fn set_allow_negative_corrections(&mut self, enabled: bool) {
+        self.allow_negative_corrections = enabled;
+    }
 
-                if record.available_amounts < amount {
-                    return Err(anyhow!(ProcessTransactionError::InsufficientFunds(
-                        txn.tx_id,
-                        txn.txn_type,
-                    )));
-                }
+    /// When enabled, a resolve or chargeback of a deposit that has no open dispute
+    /// is treated as out of order noise and ignored instead of rejected.
+    pub fn set_ignore_missing_disputes(&mut self, enabled: bool) {
+        self.ignore_missing_disputes = enabled;
+    }
 
-                record.available_amounts -= amount;
-                self.withdrawals.insert(txn.tx_id);
-            }
-            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
-                if let Some(Deposit {
-                    status,
-                    client_id,
-                    amount,
-                }) = self.deposits.get_mut(&txn.tx_id)
-                {
-                    if Some(*status) != txn.txn_type.get_preceding_txn_state() {
-                        return Err(anyhow!(ProcessTransactionError::InvalidTransactionState(
-                            txn.tx_id,
-                            txn.txn_type,
-                            *status,
-                        )));
-                    }
-                    if *client_id != txn.client_id {
-                        return Err(anyhow!(ProcessTransactionError::MissingTransaction(
-                            txn.tx_id,
-                            txn.client_id,
-                            txn.txn_type,
-                        )));
-                    }
+    /// When enabled, a deposit or withdrawal that exactly repeats one already
+    /// applied, e.g. because it appears in two overlapping daily files, is skipped
+    /// instead of rejected. One that reuses the tx id for a different client, type
+    /// or amount is still rejected as a duplicate.
+    pub fn set_dedupe_across_files(&mut self, enabled: bool) {
+        self.dedupe_across_files = enabled;
+    }
 
-                    match txn.txn_type {
-                        TransactionType::Dispute => {
-                            if record.available_amounts >= *amount {
-                                record.available_amounts -= *amount;
-                                record.held_amounts += *amount;
-                                *status = TransactionType::Dispute;
-                            } else {
-                                return Err(anyhow!(ProcessTransactionError::InsufficientFunds(
-                                    txn.tx_id,
-                                    txn.txn_type,
-                                )));
-                            }
-                        }
-                        TransactionType::Resolve => {
-                            if record.held_amounts < *amount {
-                                return Err(anyhow!("logic error: held funds should never be insufficient for a resolve"));
-                            }
+    /// Makes withdrawals two-phase. A withdrawal moves its amount from available to
+    /// pending, and a later `settle` removes it from the account while a `cancel`
+    /// returns it to available. Only settled withdrawals can be disputed.
+    pub fn set_pending_withdrawals(&mut self, enabled: bool) {
+        self.pending_withdrawals = enabled;
+    }
 
-                            record.available_amounts += *amount;
-                            record.held_amounts -= *amount;
-                            *status = TransactionType::Resolve;
-                        }
-                        TransactionType::Chargeback => {
-                            if record.held_amounts < *amount {
-                                return Err(anyhow!("logic error: held funds should never be insufficient for a chargeback"));
-                            }
-                            record.held_amounts -= *amount;
-                            *status = TransactionType::Chargeback;
-                            record.is_locked = true
-                        }
-                        _ => unreachable!(),
-                    };
-                } else {
-                    return Err(anyhow!(ProcessTransactionError::MissingTransaction(
-                        txn.tx_id,
-                        txn.client_id,
-                        txn.txn_type,
-                    )));
-                }
-            }
-        }
+    /// Checks every transaction that takes funds out of an account before applying
+    /// it, and fails with a fatal error, leaving the records unchanged, if it would
+    /// leave its client's total below zero. This catches configurations, such as an
+    /// overdraft limit, that let balances go negative by mistake. Reversals are
+    /// exempt, since taking back a deposit is meant to.
+    pub fn set_strict_balance(&mut self, enabled: bool) {
+        self.strict_balance = enabled;
+    }
 
-        Ok(())
+    /// By default a locked account only blocks withdrawals, so a dispute on another
+    /// deposit still moves funds to held. When enabled, disputes and resolves on a
+    /// locked account are rejected as well. Chargebacks of disputes that were
+    /// already open are still allowed, since they never release funds to the client.
+    pub fn set_lock_freezes_all(&mut self, enabled: bool) {
+        self.lock_freezes_all = enabled;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use spectral::prelude::*;
+    /// Sets which funds a dispute has to be covered by. Defaults to
+    /// `DisputePolicy::CurrentAvailable`.
+    pub fn set_dispute_policy(&mut self, policy: DisputePolicy) {
+        self.dispute_policy = policy;
+    }
 
-    macro_rules! check_client {
-        ($cr:ident, $id:literal, $aa:literal, $ha:literal) => {
-            let client = $cr.view().get(&$id);
-            assert_that!(client)
-                .is_some()
-                .map(|c| &c.available_amounts)
-                .is_equal_to($aa);
-            assert_that!(client)
-                .is_some()
-                .map(|c| &c.held_amounts)
-                .is_equal_to($ha);
-            assert_that!(client)
-                .is_some()
-                .matches(|c| c.available_amounts + c.held_amounts == c.total_amounts())
-        };
+    /// Sets the tolerance used when checking whether a balance covers an amount, so
+    /// that float representation error doesn't reject e.g. withdrawing exactly the
+    /// available balance. Defaults to `DEFAULT_EPSILON`.
+    pub fn set_epsilon(&mut self, epsilon: f32) {
+        self.epsilon = epsilon;
     }
 
-    #[test]
-    fn it_should_process_a_single_deposit() {
-        let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+    /// Caps the funds a single client can have held. A dispute that would push the
+    /// client's held funds above the cap is rejected and the funds stay available.
+    pub fn set_max_held(&mut self, max_held: f32) {
+        self.max_held = Some(max_held);
+    }
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
+    /// Lets a withdrawal draw the available funds down to `-limit` instead of zero.
+    pub fn set_overdraft_limit(&mut self, limit: f32) {
+        self.overdraft_limit = Some(limit);
     }
 
-    #[test]
-    fn it_should_process_a_deposit_followed_by_a_valid_withdrawal() {
-        let mut client_records = ClientRecords::new();
-        let client_id = 1;
+    /// Sets how applied deposits and withdrawals are remembered for duplicate
+    /// detection. Defaults to an `ExactDetector`. This must be set before any
+    /// transactions are processed.
+    pub fn set_duplicate_detector(&mut self, detector: impl DuplicateDetector + 'static) {
+        self.duplicates = Box::new(detector);
+    }
 
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
-        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 2, 5.0);
+    /// When enabled, transaction ids are only unique per client, so the ledger is
+    /// keyed by `(client_id, tx_id)` and the same tx id can be used by different
+    /// clients. This must be set before any transactions are processed.
+    pub fn set_client_scoped_tx_ids(&mut self, enabled: bool) {
+        self.client_scoped_tx_ids = enabled;
+    }
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+    pub fn view(&self) -> &HashMap<ClientId, Client> {
+        &self.records
+    }
 
-        check_client!(client_records, 1, 5.0, 0.0);
+    /// Consumes the records, returning every client ordered by client id without
+    /// cloning them.
+    pub fn into_clients(self) -> Vec<Client> {
+        let mut clients: Vec<Client> = self.records.into_values().collect();
+        clients.sort_by_key(|c| c.client_id);
+        clients
     }
 
-    #[test]
-    fn it_should_fail_to_process_a_withdrawal_if_there_are_insufficient_available_funds() {
-        let mut client_records = ClientRecords::new();
-        let client_id = 1;
+    /// Copies every client, ordered by client id. The copy shares nothing with the
+    /// records, so a service processing transactions behind a lock can hand it to
+    /// readers and release the lock instead of letting them hold it while they read.
+    pub fn snapshot(&self) -> Vec<Client> {
+        self.sorted_clients().into_iter().cloned().collect()
+    }
 
-        let withdrawal_txn_1 = Transaction::new_withdrawal_txn(client_id, 1, 1.0);
+    pub(crate) fn sorted_clients(&self) -> Vec<&Client> {
+        let mut clients: Vec<&Client> = self.records.values().collect();
+        clients.sort_by_key(|c| c.client_id);
+        clients
+    }
 
-        assert_that(&client_records.process_transaction(&withdrawal_txn_1))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::InsufficientFunds(
-                    1,
-                    TransactionType::Withdrawal,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 0.0, 0.0);
+    /// Moves `amount` between a client's available and held funds, e.g. to correct
+    /// a misapplied dispute. The total is unchanged. Fails without touching the
+    /// balances if the `from` bucket does not cover the amount.
+    pub fn adjust(
+        &mut self,
+        client_id: ClientId,
+        from: Bucket,
+        to: Bucket,
+        amount: f32,
+    ) -> anyhow::Result<()> {
+        if amount.is_nan() || amount < 0.0 {
+            return Err(anyhow!(
+                "adjustment amount: {} must not be negative",
+                amount
+            ));
+        }
 
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 2, 10.0);
-        let withdrawal_txn_2 = Transaction::new_withdrawal_txn(client_id, 3, 15.0);
+        let record = self.records.get_mut(&client_id).ok_or_else(|| {
+            anyhow!(ProcessTransactionError::AdjustmentExceedsBalance(
+                client_id, from
+            ))
+        })?;
+        if !covers(*record.balance_mut(from), amount, self.epsilon) {
+            return Err(anyhow!(ProcessTransactionError::AdjustmentExceedsBalance(
+                client_id, from
+            )));
+        }
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        assert_that(&client_records.process_transaction(&withdrawal_txn_2))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::InsufficientFunds(
-                    3,
-                    TransactionType::Withdrawal,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 10.0, 0.0);
+        if from != to {
+            let from_balance = record.balance_mut(from);
+            *from_balance = (*from_balance - amount).max(0.0);
+            *record.balance_mut(to) += amount;
+        }
+
+        Ok(())
+    }
+
+    /// Applies a manual correction to a client's balances and records it, along with
+    /// the reason, in the corrections log. Fails without recording anything if the
+    /// client's total would drop below zero, unless negative corrections are allowed.
+    pub fn apply_correction(
+        &mut self,
+        client_id: ClientId,
+        delta_available: f32,
+        delta_held: f32,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        let total = self
+            .records
+            .get(&client_id)
+            .map_or(0.0, Client::total_amounts)
+            + delta_available
+            + delta_held;
+        if !self.allow_negative_corrections && total + self.epsilon < 0.0 {
+            return Err(anyhow!(ProcessTransactionError::NegativeCorrection(
+                client_id
+            )));
+        }
+
+        let sequence = self.correction_sequence.advance()?;
+        let record = self
+            .records
+            .entry(client_id)
+            .or_insert_with(|| Client::new(client_id));
+        record.available_amounts += delta_available;
+        record.held_amounts += delta_held;
+        self.corrections.push(Correction {
+            sequence,
+            client_id,
+            delta_available,
+            delta_held,
+            reason: reason.to_string(),
+        });
+
+        Ok(())
+    }
+
+    /// Every correction applied so far, oldest first.
+    pub fn corrections(&self) -> &[Correction] {
+        &self.corrections
+    }
+
+    /// Whether the deposit has been resolved at least once, including deposits that
+    /// were disputed again after being resolved.
+    pub fn was_resolved(&self, client_id: ClientId, tx_id: u32) -> bool {
+        self.ledger
+            .deposit(self.ledger_key(client_id, tx_id))
+            .is_some_and(|deposit| deposit.client_id == client_id && deposit.was_resolved)
+    }
+
+    /// Takes the clients finalized since the last call, in the order their finalize
+    /// transactions were processed. Their balances can no longer change, so they can
+    /// be written out before the rest of the input has been processed.
+    pub fn drain_finalized(&mut self) -> Vec<Client> {
+        self.pending_flush
+            .drain(..)
+            .filter_map(|client_id| self.records.get(&client_id).cloned())
+            .collect()
+    }
+
+    /// Whether a finalize transaction has been processed for the client.
+    pub fn is_finalized(&self, client_id: ClientId) -> bool {
+        self.completed_clients.contains(&client_id)
+    }
+
+    /// Clients whose held funds make up more than `threshold` of their total, e.g.
+    /// `0.5` for accounts with more than half of their funds disputed, ordered by
+    /// client id.
+    pub fn high_held(&self, threshold: f32) -> Vec<&Client> {
+        let mut clients = self.sorted_clients();
+        clients.retain(|client| client.held_ratio() > threshold);
+        clients
+    }
+
+    /// Counts the clients whose total falls into each bucket. `buckets` holds the
+    /// ascending lower bounds of the buckets, so each one runs up to the next bound
+    /// and the last one has no upper bound. Clients below the first bound are not
+    /// counted.
+    pub fn balance_histogram(&self, buckets: &[f32]) -> Vec<(f32, usize)> {
+        let mut counts = vec![0; buckets.len()];
+        for client in self.records.values() {
+            let above = buckets.partition_point(|bound| *bound <= client.total_amounts());
+            if let Some(index) = above.checked_sub(1) {
+                counts[index] += 1;
+            }
+        }
+
+        buckets.iter().copied().zip(counts).collect()
+    }
+
+    /// Checks the invariants that tie the ledger to the client balances: every
+    /// open dispute of a deposit is covered by its client's held funds, no total is
+    /// below zero (or below the overdraft limit), and every ledger entry belongs to
+    /// a known client. Meant for debugging and tests, since it scans the whole
+    /// ledger. Fails with a description of the first violation found.
+    pub fn validate_ledger_integrity(&self) -> anyhow::Result<()> {
+        let mut disputed: HashMap<ClientId, f32> = HashMap::new();
+        let mut reversed: HashMap<ClientId, f32> = HashMap::new();
+        for ((_, tx_id), deposit) in self.ledger.deposits() {
+            if !self.records.contains_key(&deposit.client_id) {
+                return Err(anyhow!(
+                    "deposit: \"{}\" belongs to client: {} which has no record",
+                    tx_id,
+                    deposit.client_id
+                ));
+            }
+            if deposit.status == TransactionType::Dispute {
+                *disputed.entry(deposit.client_id).or_default() += deposit.disputed_amount;
+            }
+            if deposit.status == TransactionType::Reversal {
+                *reversed.entry(deposit.client_id).or_default() += deposit.amount;
+            }
+        }
+        for ((_, tx_id), withdrawal) in self.ledger.withdrawals() {
+            if !self.records.contains_key(&withdrawal.client_id) {
+                return Err(anyhow!(
+                    "withdrawal: \"{}\" belongs to client: {} which has no record",
+                    tx_id,
+                    withdrawal.client_id
+                ));
+            }
+        }
+
+        for client in self.sorted_clients() {
+            let disputed = disputed.get(&client.client_id).copied().unwrap_or(0.0);
+            if !covers(client.held_amounts, disputed, self.epsilon) {
+                return Err(anyhow!(
+                    "client: {} holds {:.4} but has {:.4} in open disputes",
+                    client.client_id,
+                    client.held_amounts,
+                    disputed
+                ));
+            }
+
+            // Each reversed deposit may have taken the total further below zero.
+            let min_total = self.overdraft_limit.map_or(0.0, |limit| -limit)
+                - reversed.get(&client.client_id).copied().unwrap_or(0.0);
+            if !self.allow_negative_corrections
+                && !covers(client.total_amounts(), min_total, self.epsilon)
+            {
+                return Err(anyhow!(
+                    "client: {} has a total of {:.4}, below the minimum of {:.4}",
+                    client.client_id,
+                    client.total_amounts(),
+                    min_total
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists how every client's balances changed since `prev`, ordered by client id.
+    /// Clients missing from either snapshot are compared against a zero balance, and
+    /// clients that did not change are left out.
+    pub fn diff(&self, prev: &ClientRecords<S>) -> Vec<ClientDelta> {
+        let mut client_ids: Vec<ClientId> = self
+            .records
+            .keys()
+            .chain(prev.records.keys())
+            .copied()
+            .collect::<HashSet<ClientId>>()
+            .into_iter()
+            .collect();
+        client_ids.sort_unstable();
+
+        client_ids
+            .into_iter()
+            .filter_map(|client_id| {
+                let empty = Client::new(client_id);
+                let current = self.records.get(&client_id).unwrap_or(&empty);
+                let previous = prev.records.get(&client_id).unwrap_or(&empty);
+                let delta = ClientDelta {
+                    client_id,
+                    d_available: current.available_amounts - previous.available_amounts,
+                    d_held: current.held_amounts - previous.held_amounts,
+                    d_total: current.total_amounts() - previous.total_amounts(),
+                    locked_changed: current.is_locked() != previous.is_locked(),
+                };
+                let changed = delta.d_available != 0.0
+                    || delta.d_held != 0.0
+                    || delta.d_total != 0.0
+                    || delta.locked_changed;
+                changed.then_some(delta)
+            })
+            .collect()
+    }
+
+    /// Processes a batch of transactions in order, in a single pass. Rejected
+    /// transactions are skipped and returned alongside their tx id, so a later
+    /// transaction in the batch sees every earlier one that was applied. A fatal
+    /// error stops the batch and is returned as `Err`.
+    pub fn process_transactions(
+        &mut self,
+        txns: impl IntoIterator<Item = impl Borrow<Transaction>>,
+    ) -> Result<Vec<(u32, ProcessTransactionError)>, ProcessTransactionError> {
+        let mut rejected = vec![];
+        for txn in txns {
+            let txn = txn.borrow();
+            if let Err(err) = self.process_transaction(txn) {
+                if !err.is_recoverable() {
+                    return Err(err);
+                }
+                rejected.push((txn.tx_id, err));
+            }
+        }
+
+        Ok(rejected)
+    }
+
+    /// Applies every transaction in the batch or none of them. The batch is processed
+    /// against a copy of the records, which only replaces `self` if every transaction
+    /// succeeds; otherwise the first error is returned and `self` is left untouched.
+    pub fn process_atomic(&mut self, txns: &[Transaction]) -> Result<(), ProcessTransactionError> {
+        let mut staged = self.clone();
+        for txn in txns {
+            staged.process_transaction(txn)?;
+        }
+
+        *self = staged;
+
+        Ok(())
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint<S> {
+        Checkpoint(self.clone())
+    }
+
+    pub fn rollback(&mut self, checkpoint: Checkpoint<S>) {
+        *self = checkpoint.0;
+    }
+
+    fn ledger_key(&self, client_id: ClientId, tx_id: u32) -> LedgerKey {
+        if self.client_scoped_tx_ids {
+            (Some(client_id), tx_id)
+        } else {
+            (None, tx_id)
+        }
+    }
+
+    /// Whether a deposit or withdrawal with this tx id has been applied, so that
+    /// resubmitting it would be rejected as a duplicate. The client id is only used
+    /// when transaction ids are scoped per client.
+    pub fn is_processed(&self, client_id: ClientId, tx_id: u32) -> bool {
+        self.is_txn_processed(self.ledger_key(client_id, tx_id))
+    }
+
+    fn is_txn_processed(&self, key: LedgerKey) -> bool {
+        self.duplicates.contains(key)
+    }
+
+    /// Applies a single transaction. If it is rejected, its client (when it has a
+    /// record) is flagged as having had errors, and if it was rejected because the
+    /// account is frozen it is kept as a dead letter. Use
+    /// `ProcessTransactionError::is_recoverable` to tell a rejected transaction
+    /// apart from a fatal error.
+    ///
+    /// This never panics, whatever the input. A broken invariant that would
+    /// otherwise panic is returned as a fatal `ProcessTransactionError::Internal`.
+    pub fn process_transaction(
+        &mut self,
+        txn: &Transaction,
+    ) -> Result<(), ProcessTransactionError> {
+        let result = self.apply_transaction(txn);
+        if let (Ok(()), Some(event_id)) = (&result, &txn.event_id) {
+            self.applied_events
+                .insert(event_id.clone(), (txn.txn_type, txn.client_id, txn.tx_id));
+        }
+        if let Err(err) = &result {
+            if let Some(record) = self.records.get_mut(&txn.client_id) {
+                record.had_errors = true;
+            }
+            if let ProcessTransactionError::ClientAccountFrozen(..) = err {
+                self.dead_letters.push(txn.clone());
+            }
+        }
+
+        result
+    }
+
+    /// Transactions rejected because their client's account was frozen, in the
+    /// order they were processed. They can be reviewed and resubmitted once the
+    /// account has been unlocked.
+    pub fn dead_letters(&self) -> &[Transaction] {
+        &self.dead_letters
+    }
+
+    fn apply_transaction(&mut self, txn: &Transaction) -> Result<(), ProcessTransactionError> {
+        if let Some(event_id) = &txn.event_id {
+            match self.applied_events.get(event_id) {
+                // The exact same event was already applied, so this is a replay.
+                Some(applied) if *applied == (txn.txn_type, txn.client_id, txn.tx_id) => {
+                    return Ok(())
+                }
+                Some(_) => {
+                    return Err(ProcessTransactionError::EventIdConflict(
+                        txn.tx_id,
+                        txn.txn_type,
+                        event_id.clone(),
+                    ))
+                }
+                None => {}
+            }
+        }
+
+        if self.completed_clients.contains(&txn.client_id) {
+            return Err(ProcessTransactionError::ClientFinalized(
+                txn.tx_id,
+                txn.txn_type,
+                txn.client_id,
+            ));
+        }
+
+        let key = self.ledger_key(txn.client_id, txn.tx_id);
+        let is_txn_processed = self.is_txn_processed(key);
+        // A new client is only added once its transaction has been applied, so a
+        // rejected transaction never leaves a phantom zero balance client behind.
+        let mut new_record = None;
+        let record = match self.records.get_mut(&txn.client_id) {
+            Some(record) => record,
+            // A transaction referencing a deposit can only succeed for the client
+            // that made it.
+            None if !txn.txn_type.get_preceding_txn_states().is_empty() => {
+                return Err(self.missing_deposit_error(txn, key))
+            }
+            // Unlocking or finalizing an unknown client changes nothing, but no
+            // transactions are accepted for it after it has been finalized.
+            None if matches!(
+                txn.txn_type,
+                TransactionType::Unlock | TransactionType::Finalize
+            ) =>
+            {
+                if txn.txn_type == TransactionType::Finalize {
+                    self.completed_clients.insert(txn.client_id);
+                }
+                return Ok(());
+            }
+            None => new_record.insert(Client::new(txn.client_id)),
+        };
+        let amount = txn.amount.unwrap_or(0.0);
+
+        if self.seal_on_chargeback
+            && record.is_locked()
+            && !matches!(
+                txn.txn_type,
+                TransactionType::Unlock | TransactionType::Finalize
+            )
+        {
+            return Err(ProcessTransactionError::ClientAccountFrozen(
+                txn.tx_id,
+                txn.txn_type,
+                txn.client_id,
+            ));
+        }
+
+        match txn.txn_type {
+            TransactionType::Deposit => {
+                if is_txn_processed {
+                    return self.repeated_transaction(txn, key);
+                }
+
+                if self.strict && amount == 0.0 {
+                    return Err(ProcessTransactionError::ZeroAmount(txn.tx_id, txn.txn_type));
+                }
+
+                if self
+                    .min_transaction_amount
+                    .is_some_and(|min_amount| amount < min_amount)
+                {
+                    return Err(ProcessTransactionError::AmountTooSmall(txn.tx_id, amount));
+                }
+
+                record.apply_deposit(amount);
+                let flow = self.flows.entry(txn.client_id).or_default();
+                self.duplicates.insert(key);
+                self.ledger.insert_deposit(
+                    key,
+                    Deposit {
+                        client_id: txn.client_id,
+                        amount,
+                        status: TransactionType::Deposit,
+                        was_resolved: false,
+                        disputed_amount: 0.0,
+                        deposited_before: flow.deposited,
+                    },
+                );
+                flow.deposited += amount;
+            }
+            TransactionType::Withdrawal => {
+                if is_txn_processed {
+                    return self.repeated_transaction(txn, key);
+                }
+
+                if self.strict && amount == 0.0 {
+                    return Err(ProcessTransactionError::ZeroAmount(txn.tx_id, txn.txn_type));
+                }
+
+                if self
+                    .min_transaction_amount
+                    .is_some_and(|min_amount| amount < min_amount)
+                {
+                    return Err(ProcessTransactionError::AmountTooSmall(txn.tx_id, amount));
+                }
+
+                if record.is_locked() {
+                    return Err(ProcessTransactionError::ClientAccountFrozen(
+                        txn.tx_id,
+                        txn.txn_type,
+                        txn.client_id,
+                    ));
+                }
+
+                let overdraft = self.overdraft_limit.unwrap_or(0.0);
+                record
+                    .apply_checked(self.strict_balance, self.epsilon, |record| {
+                        record.apply_withdrawal(amount, overdraft, self.epsilon)?;
+                        if self.pending_withdrawals {
+                            record.pending_amounts += amount;
+                        }
+                        Ok(())
+                    })
+                    .map_err(|err| err.for_transaction(txn))?;
+                self.flows.entry(txn.client_id).or_default().withdrawn += amount;
+                self.duplicates.insert(key);
+                self.ledger.insert_withdrawal(
+                    key,
+                    Withdrawal {
+                        client_id: txn.client_id,
+                        amount,
+                        status: TransactionType::Withdrawal,
+                    },
+                );
+            }
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Void
+            | TransactionType::Reversal => {
+                if let Some(Deposit {
+                    status,
+                    client_id,
+                    amount,
+                    was_resolved,
+                    disputed_amount,
+                    deposited_before,
+                }) = self.ledger.deposit_mut(key)
+                {
+                    if !txn.txn_type.get_preceding_txn_states().contains(status) {
+                        if self.ignore_missing_disputes
+                            && *client_id == txn.client_id
+                            && is_missing_dispute(txn.txn_type, *status)
+                        {
+                            return Ok(());
+                        }
+                        return Err(ProcessTransactionError::InvalidTransactionState(
+                            txn.tx_id,
+                            txn.txn_type,
+                            *status,
+                        ));
+                    }
+                    if *client_id != txn.client_id {
+                        return Err(ProcessTransactionError::MissingTransaction(
+                            txn.tx_id,
+                            txn.client_id,
+                            txn.txn_type,
+                        ));
+                    }
+                    if self.lock_freezes_all
+                        && record.is_locked()
+                        && matches!(
+                            txn.txn_type,
+                            TransactionType::Dispute | TransactionType::Resolve
+                        )
+                    {
+                        return Err(ProcessTransactionError::ClientAccountFrozen(
+                            txn.tx_id,
+                            txn.txn_type,
+                            txn.client_id,
+                        ));
+                    }
+
+                    match txn.txn_type {
+                        TransactionType::Dispute => {
+                            // A dispute with an amount only holds that part of the deposit.
+                            let dispute_amount = txn.amount.unwrap_or(*amount);
+                            if !(dispute_amount > 0.0 && dispute_amount <= *amount) {
+                                return Err(ProcessTransactionError::InvalidDisputeAmount(
+                                    txn.tx_id,
+                                    dispute_amount,
+                                ));
+                            }
+                            if self.max_held.is_some_and(|max_held| {
+                                record.held_amounts + dispute_amount > max_held
+                            }) {
+                                return Err(ProcessTransactionError::HeldLimitExceeded(
+                                    txn.tx_id,
+                                    txn.client_id,
+                                ));
+                            }
+                            if self.dispute_policy == DisputePolicy::OriginalFunds {
+                                // Withdrawals draw from the oldest deposits first, so
+                                // this deposit is drawn from once the withdrawals add
+                                // up to more than the deposits before it that are
+                                // still in the account.
+                                let left_over =
+                                    self.flows.get(&txn.client_id).map_or(*amount, |flow| {
+                                        flow.left_over(*deposited_before, *amount)
+                                    });
+                                if !covers(left_over, dispute_amount, self.epsilon) {
+                                    return Err(ProcessTransactionError::InsufficientFunds(
+                                        txn.tx_id,
+                                        txn.txn_type,
+                                    ));
+                                }
+                            }
+
+                            record
+                                .hold(dispute_amount, self.epsilon)
+                                .map_err(|err| err.for_transaction(txn))?;
+                            *status = TransactionType::Dispute;
+                            *disputed_amount = dispute_amount;
+                        }
+                        TransactionType::Resolve => {
+                            // Only the disputed part of the deposit was held, so only
+                            // that part is released.
+                            record
+                                .release(*disputed_amount, self.epsilon)
+                                .map_err(|err| err.for_transaction(txn))?;
+                            *status = TransactionType::Resolve;
+                            *was_resolved = true;
+                        }
+                        TransactionType::Chargeback => {
+                            record
+                                .apply_checked(self.strict_balance, self.epsilon, |record| {
+                                    record.charge_back(*disputed_amount, self.epsilon)
+                                })
+                                .map_err(|err| err.for_transaction(txn))?;
+                            self.flows
+                                .entry(txn.client_id)
+                                .or_default()
+                                .removed
+                                .push((*deposited_before, *disputed_amount));
+                            *status = TransactionType::Chargeback;
+                        }
+                        TransactionType::Void => {
+                            // Voiding takes the deposit back out like a withdrawal
+                            // would, so it is checked the same way.
+                            record
+                                .apply_checked(self.strict_balance, self.epsilon, |record| {
+                                    record.apply_withdrawal(*amount, 0.0, self.epsilon)
+                                })
+                                .map_err(|err| err.for_transaction(txn))?;
+                            self.flows
+                                .entry(txn.client_id)
+                                .or_default()
+                                .removed
+                                .push((*deposited_before, *amount));
+                            *status = TransactionType::Void;
+                        }
+                        TransactionType::Reversal => {
+                            // A clawback takes the whole deposit back even if some
+                            // of it was withdrawn since.
+                            record.reverse_deposit(*amount);
+                            self.flows
+                                .entry(txn.client_id)
+                                .or_default()
+                                .removed
+                                .push((*deposited_before, *amount));
+                            *status = TransactionType::Reversal;
+                        }
+                        _ => {
+                            return Err(ProcessTransactionError::Internal(
+                                txn.tx_id,
+                                txn.txn_type,
+                                "not a transaction that references a deposit",
+                            ))
+                        }
+                    };
+                } else if self.withdrawal_disputes
+                    && !matches!(
+                        txn.txn_type,
+                        TransactionType::Void | TransactionType::Reversal
+                    )
+                    && self.ledger.withdrawal(key).is_some()
+                {
+                    self.apply_withdrawal_dispute(txn, key)?;
+                } else {
+                    return Err(self.missing_deposit_error(txn, key));
+                }
+
+                if self.prune_finalized {
+                    self.prune_if_finalized(key);
+                }
+            }
+            TransactionType::Settle | TransactionType::Cancel => {
+                let withdrawal = match self.ledger.withdrawal_mut(key) {
+                    Some(withdrawal) if withdrawal.client_id == txn.client_id => withdrawal,
+                    _ => {
+                        return Err(ProcessTransactionError::MissingTransaction(
+                            txn.tx_id,
+                            txn.client_id,
+                            txn.txn_type,
+                        ))
+                    }
+                };
+                if !self.pending_withdrawals || withdrawal.status != TransactionType::Withdrawal {
+                    return Err(ProcessTransactionError::WithdrawalNotPending(
+                        txn.tx_id,
+                        txn.txn_type,
+                    ));
+                }
+
+                let amount = withdrawal.amount;
+                record
+                    .apply_checked(self.strict_balance, self.epsilon, |record| {
+                        record.pending_amounts = (record.pending_amounts - amount).max(0.0);
+                        if txn.txn_type == TransactionType::Cancel {
+                            record.available_amounts += amount;
+                        }
+                        Ok(())
+                    })
+                    .map_err(|err| err.for_transaction(txn))?;
+                if txn.txn_type == TransactionType::Cancel {
+                    self.flows.entry(txn.client_id).or_default().withdrawn -= withdrawal.amount;
+                }
+                withdrawal.status = txn.txn_type;
+            }
+            TransactionType::Unlock => {
+                record.lock_reason = None;
+            }
+            TransactionType::Finalize => {
+                self.completed_clients.insert(txn.client_id);
+                self.pending_flush.push(txn.client_id);
+            }
+        }
+
+        if let Some(record) = new_record {
+            self.records.insert(txn.client_id, record);
+        }
+        if let (Some(reference), Some(record)) =
+            (&txn.reference, self.records.get_mut(&txn.client_id))
+        {
+            record.last_reference = Some(reference.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Disputes of withdrawals run in the opposite direction to deposits. The funds
+    /// already left the account, so a dispute holds nothing and a resolve confirms
+    /// the withdrawal as legitimate, while a chargeback reverses it by crediting the
+    /// amount back to available and locks the account.
+    fn apply_withdrawal_dispute(
+        &mut self,
+        txn: &Transaction,
+        key: LedgerKey,
+    ) -> Result<(), ProcessTransactionError> {
+        let Some(withdrawal) = self.ledger.withdrawal_mut(key) else {
+            return Err(ProcessTransactionError::Internal(
+                txn.tx_id,
+                txn.txn_type,
+                "the disputed withdrawal does not exist",
+            ));
+        };
+        let preceding_txn_state = match txn.txn_type {
+            // A pending withdrawal has to be settled before it can be disputed.
+            TransactionType::Dispute if self.pending_withdrawals => TransactionType::Settle,
+            TransactionType::Dispute => TransactionType::Withdrawal,
+            _ => TransactionType::Dispute,
+        };
+        if withdrawal.status != preceding_txn_state {
+            return Err(ProcessTransactionError::InvalidTransactionState(
+                txn.tx_id,
+                txn.txn_type,
+                withdrawal.status,
+            ));
+        }
+        if withdrawal.client_id != txn.client_id {
+            return Err(ProcessTransactionError::MissingTransaction(
+                txn.tx_id,
+                txn.client_id,
+                txn.txn_type,
+            ));
+        }
+
+        let Some(record) = self.records.get_mut(&txn.client_id) else {
+            return Err(ProcessTransactionError::Internal(
+                txn.tx_id,
+                txn.txn_type,
+                "the client of the withdrawal has no record",
+            ));
+        };
+        if self.lock_freezes_all
+            && record.is_locked()
+            && txn.txn_type != TransactionType::Chargeback
+        {
+            return Err(ProcessTransactionError::ClientAccountFrozen(
+                txn.tx_id,
+                txn.txn_type,
+                txn.client_id,
+            ));
+        }
+
+        if txn.txn_type == TransactionType::Chargeback {
+            record.available_amounts += withdrawal.amount;
+            self.flows.entry(txn.client_id).or_default().withdrawn -= withdrawal.amount;
+            record.lock_reason = Some(LockReason::WithdrawalChargeback);
+        }
+        withdrawal.status = txn.txn_type;
+
+        Ok(())
+    }
+
+    /// The result of a deposit or withdrawal whose tx id was already used. It is
+    /// skipped if it exactly repeats the applied transaction and repeats are
+    /// allowed, and rejected as a duplicate otherwise. A pruned transaction can no
+    /// longer be compared, so repeating it is always a duplicate.
+    fn repeated_transaction(
+        &self,
+        txn: &Transaction,
+        key: LedgerKey,
+    ) -> Result<(), ProcessTransactionError> {
+        let amount = txn.amount.unwrap_or(0.0);
+        let is_repeat = match txn.txn_type {
+            TransactionType::Deposit => self.ledger.deposit(key).is_some_and(|deposit| {
+                deposit.client_id == txn.client_id && deposit.amount == amount
+            }),
+            TransactionType::Withdrawal => self.ledger.withdrawal(key).is_some_and(|withdrawal| {
+                withdrawal.client_id == txn.client_id && withdrawal.amount == amount
+            }),
+            _ => false,
+        };
+        if self.dedupe_across_files && is_repeat {
+            return Ok(());
+        }
+
+        Err(ProcessTransactionError::DuplicateTransaction(txn.tx_id))
+    }
+
+    /// The error for a transaction whose referenced deposit could not be found.
+    fn missing_deposit_error(&self, txn: &Transaction, key: LedgerKey) -> ProcessTransactionError {
+        if txn.txn_type == TransactionType::Dispute && self.ledger.withdrawal(key).is_some() {
+            ProcessTransactionError::CannotDisputeWithdrawal(txn.tx_id)
+        } else {
+            ProcessTransactionError::MissingTransaction(txn.tx_id, txn.client_id, txn.txn_type)
+        }
+    }
+
+    /// Drops a deposit from the ledger once it reaches a state it can never leave,
+    /// keeping only its key so that the tx id is still detected as a duplicate.
+    fn prune_if_finalized(&mut self, key: LedgerKey) {
+        // Reversed deposits are kept, since they account for a negative balance.
+        if self.ledger.deposit(key).is_some_and(|deposit| {
+            deposit.status.is_final_state() && deposit.status != TransactionType::Reversal
+        }) {
+            self.ledger.remove_deposit(key);
+        }
+
+        // Every withdrawal is in a final state as far as deposits are concerned, so
+        // only prune the ones whose dispute has been settled.
+        if self.ledger.withdrawal(key).is_some_and(|withdrawal| {
+            matches!(
+                withdrawal.status,
+                TransactionType::Resolve | TransactionType::Chargeback
+            )
+        }) {
+            self.ledger.remove_withdrawal(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    macro_rules! check_client {
+        ($cr:ident, $id:literal, $aa:literal, $ha:literal) => {
+            let client = $cr.view().get(&$id);
+            assert_that!(client)
+                .is_some()
+                .map(|c| &c.available_amounts)
+                .is_equal_to($aa);
+            assert_that!(client)
+                .is_some()
+                .map(|c| &c.held_amounts)
+                .is_equal_to($ha);
+            assert_that!(client).is_some().matches(|c| {
+                c.available_amounts + c.held_amounts + c.pending_amounts == c.total_amounts()
+            })
+        };
+    }
+
+    #[test]
+    fn it_should_process_a_single_deposit() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_process_a_deposit_followed_by_a_valid_withdrawal() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 2, 5.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+
+        check_client!(client_records, 1, 5.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_process_a_withdrawal_if_there_are_insufficient_available_funds() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+
+        let withdrawal_txn_1 = Transaction::new_withdrawal_txn(client_id, 1, 1.0);
+
+        assert_that(&client_records.process_transaction(&withdrawal_txn_1)).is_err_containing(
+            ProcessTransactionError::InsufficientFunds(1, TransactionType::Withdrawal),
+        );
+        assert_that!(client_records.view().get(&1)).is_none();
+
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 2, 10.0);
+        let withdrawal_txn_2 = Transaction::new_withdrawal_txn(client_id, 3, 15.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        assert_that(&client_records.process_transaction(&withdrawal_txn_2)).is_err_containing(
+            ProcessTransactionError::InsufficientFunds(3, TransactionType::Withdrawal),
+        );
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_process_the_same_deposit_twice_by_txn_id() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 1, 123.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn_2))
+            .is_err_containing(ProcessTransactionError::DuplicateTransaction(1));
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_process_the_same_withdrawal_twice_by_txn_id() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let withdrawal_txn_1 = Transaction::new_withdrawal_txn(client_id, 2, 1.0);
+
+        assert_that(&client_records.process_transaction(&withdrawal_txn_1)).is_ok();
+        check_client!(client_records, 1, 9.0, 0.0);
+
+        let withdrawal_txn_2 = Transaction::new_withdrawal_txn(client_id, 2, 5.0);
+
+        assert_that(&client_records.process_transaction(&withdrawal_txn_2))
+            .is_err_containing(ProcessTransactionError::DuplicateTransaction(2));
+        check_client!(client_records, 1, 9.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_be_able_to_dispute_a_deposit_txn() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_dispute_the_same_txn_twice() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::InvalidTransactionState(
+                1,
+                TransactionType::Dispute,
+                TransactionType::Dispute,
+            ),
+        );
+        check_client!(client_records, 1, 0.0, 10.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_dispute_a_txn_that_doesnt_exist() {
+        let mut client_records = ClientRecords::new();
+
+        let dispute_txn = Transaction::new_dispute_txn(1, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::MissingTransaction(1, 1, TransactionType::Dispute),
+        );
+    }
+
+    #[test]
+    fn it_should_fail_to_dispute_a_txn_that_doesnt_exist_for_the_client() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(2, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::MissingTransaction(1, 2, TransactionType::Dispute),
+        );
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_dispute_a_txn_where_funds_are_insufficient() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let withdraw_txn = Transaction::new_withdrawal_txn(client_id, 2, 5.0);
+        assert_that(&client_records.process_transaction(&withdraw_txn)).is_ok();
+        check_client!(client_records, 1, 5.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::InsufficientFunds(1, TransactionType::Dispute),
+        );
+        check_client!(client_records, 1, 5.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_be_able_to_resolve_a_disputed_txn() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+
+        let resolve_txn = Transaction::new_resolve_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_resolve_a_txn_that_doesnt_exist() {
+        let mut client_records = ClientRecords::new();
+
+        let resolve_txn = Transaction::new_resolve_txn(1, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_err_containing(
+            ProcessTransactionError::MissingTransaction(1, 1, TransactionType::Resolve),
+        );
+    }
+
+    #[test]
+    fn it_should_be_able_to_chargeback_a_disputed_transaction() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+
+        let chargeback_txn = Transaction::new_chargeback_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&chargeback_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 0.0);
+        assert_that!(client_records.view().get(&1))
+            .is_some()
+            .map(|c| &c.lock_reason)
+            .is_equal_to(Some(LockReason::Chargeback));
+    }
+
+    #[test]
+    fn it_should_fail_to_chargeback_a_txn_that_doesnt_exist() {
+        let mut client_records = ClientRecords::new();
+
+        let chargeback_txn = Transaction::new_chargeback_txn(1, 1);
+        assert_that(&client_records.process_transaction(&chargeback_txn)).is_err_containing(
+            ProcessTransactionError::MissingTransaction(1, 1, TransactionType::Chargeback),
+        );
+    }
+
+    #[test]
+    fn it_should_fail_to_chargeback_a_txn_where_it_has_already_been_resolved() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+
+        let resolve_txn = Transaction::new_resolve_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let chargeback_txn = Transaction::new_chargeback_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&chargeback_txn)).is_err_containing(
+            ProcessTransactionError::InvalidTransactionState(
+                1,
+                TransactionType::Chargeback,
+                TransactionType::Resolve,
+            ),
+        );
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_withdraw_if_client_account_is_locked() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        let chargeback_txn = Transaction::new_chargeback_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&chargeback_txn)).is_ok();
+
+        check_client!(client_records, 1, 0.0, 0.0);
+
+        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 2, 5.0);
+        assert_that(&client_records.process_transaction(&deposit_txn_2)).is_ok();
+
+        let withdrawal_txn = Transaction::new_withdrawal_txn(1, 3, 2.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_err_containing(
+            ProcessTransactionError::ClientAccountFrozen(3, TransactionType::Withdrawal, 1),
+        );
+    }
+
+    #[test]
+    fn it_should_ignore_failed_withdrawals_from_duplicate_tx_id_checks() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+
+        let withdrawal_txn = Transaction::new_withdrawal_txn(2, 2, 2.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_err();
+
+        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 2, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn_2)).is_ok();
+    }
+
+    #[test]
+    fn it_should_fail_to_dispute_a_withdrawal_txn() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 2, 4.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 2);
+        assert_that(&client_records.process_transaction(&dispute_txn))
+            .is_err_containing(ProcessTransactionError::CannotDisputeWithdrawal(2));
+        check_client!(client_records, 1, 6.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_be_able_to_void_a_deposit_txn() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+
+        let void_txn = Transaction::new_void_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&void_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::InvalidTransactionState(
+                1,
+                TransactionType::Dispute,
+                TransactionType::Void,
+            ),
+        );
+        check_client!(client_records, 1, 0.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_fail_to_void_a_deposit_whose_funds_were_withdrawn() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 2, 8.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+
+        let void_txn = Transaction::new_void_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&void_txn)).is_err_containing(
+            ProcessTransactionError::InsufficientFunds(1, TransactionType::Void),
+        );
+        check_client!(client_records, 1, 2.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_void_a_deposit_whose_funds_only_differ_by_float_drift() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 0.1),
+            Transaction::new_withdrawal_txn(1, 2, 0.1),
+            Transaction::new_deposit_txn(1, 3, 0.4),
+            Transaction::new_withdrawal_txn(1, 4, 0.3),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        assert_that!(client_records.records[&1].available_amounts).is_less_than(0.1);
+
+        assert_that(&client_records.process_transaction(&Transaction::new_void_txn(1, 1))).is_ok();
+        check_client!(client_records, 1, 0.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_roll_back_an_atomic_batch_if_any_transaction_fails() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+
+        let batch = [
+            Transaction::new_deposit_txn(client_id, 2, 5.0),
+            Transaction::new_withdrawal_txn(client_id, 3, 100.0),
+            Transaction::new_deposit_txn(2, 4, 1.0),
+        ];
+        assert_that(&client_records.process_atomic(&batch)).is_err_containing(
+            ProcessTransactionError::InsufficientFunds(3, TransactionType::Withdrawal),
+        );
+        check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.view().get(&2)).is_none();
+        assert_that!(client_records.is_txn_processed((None, 2))).is_false();
+
+        let batch = [
+            Transaction::new_deposit_txn(client_id, 2, 5.0),
+            Transaction::new_withdrawal_txn(client_id, 3, 12.0),
+        ];
+        assert_that(&client_records.process_atomic(&batch)).is_ok();
+        check_client!(client_records, 1, 3.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_restore_the_balance_when_rolling_back_to_a_checkpoint() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+
+        let checkpoint = client_records.checkpoint();
+
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 2, 4.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+        check_client!(client_records, 1, 6.0, 0.0);
+
+        client_records.rollback(checkpoint);
+        check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.is_txn_processed((None, 2))).is_false();
+    }
+
+    #[test]
+    fn it_should_route_disputes_by_client_when_tx_ids_are_client_scoped() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_client_scoped_tx_ids(true);
+
+        let deposit_txn_1 = Transaction::new_deposit_txn(1, 1, 10.0);
+        let deposit_txn_2 = Transaction::new_deposit_txn(2, 1, 4.0);
+        assert_that(&client_records.process_transaction(&deposit_txn_1)).is_ok();
+        assert_that(&client_records.process_transaction(&deposit_txn_2)).is_ok();
+
+        let dispute_txn = Transaction::new_dispute_txn(2, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 0.0);
+        check_client!(client_records, 2, 0.0, 4.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(1, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+        check_client!(client_records, 2, 0.0, 4.0);
+
+        let duplicate_deposit_txn = Transaction::new_deposit_txn(2, 1, 1.0);
+        assert_that(&client_records.process_transaction(&duplicate_deposit_txn))
+            .is_err_containing(ProcessTransactionError::DuplicateTransaction(1));
+    }
+
+    #[test]
+    fn it_should_reject_a_reused_tx_id_for_another_client_by_default() {
+        let mut client_records = ClientRecords::new();
+
+        let deposit_txn_1 = Transaction::new_deposit_txn(1, 1, 10.0);
+        let deposit_txn_2 = Transaction::new_deposit_txn(2, 1, 4.0);
+        assert_that(&client_records.process_transaction(&deposit_txn_1)).is_ok();
+        assert_that(&client_records.process_transaction(&deposit_txn_2))
+            .is_err_containing(ProcessTransactionError::DuplicateTransaction(1));
+    }
+
+    #[test]
+    fn it_should_reject_a_dispute_that_exceeds_the_held_limit() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_max_held(15.0);
+        let client_id = 1;
+
+        let deposit_txn_1 = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 2, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn_1)).is_ok();
+        assert_that(&client_records.process_transaction(&deposit_txn_2)).is_ok();
+
+        let dispute_txn_1 = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn_1)).is_ok();
+        check_client!(client_records, 1, 10.0, 10.0);
+
+        let dispute_txn_2 = Transaction::new_dispute_txn(client_id, 2);
+        assert_that(&client_records.process_transaction(&dispute_txn_2))
+            .is_err_containing(ProcessTransactionError::HeldLimitExceeded(2, 1));
+        check_client!(client_records, 1, 10.0, 10.0);
+    }
+
+    #[test]
+    fn it_should_apply_a_dispute_before_its_deposit_only_in_two_pass_order() {
+        let txns = || {
+            vec![
+                Transaction::new_deposit_txn(1, 1, 10.0),
+                Transaction::new_dispute_txn(2, 2),
+                Transaction::new_deposit_txn(2, 2, 5.0),
+            ]
+        };
+
+        let mut client_records = ClientRecords::new();
+        let results: Vec<bool> = txns()
+            .iter()
+            .map(|txn| client_records.process_transaction(txn).is_ok())
+            .collect();
+        assert_that!(results).is_equal_to(vec![true, false, true]);
+        check_client!(client_records, 2, 5.0, 0.0);
+
+        let mut client_records = ClientRecords::new();
+        for txn in two_pass_order(txns()) {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 10.0, 0.0);
+        check_client!(client_records, 2, 0.0, 5.0);
+    }
+
+    #[test]
+    fn it_should_withdraw_exactly_the_available_balance_despite_float_error() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        for tx_id in 1..=3 {
+            let deposit_txn = Transaction::new_deposit_txn(client_id, tx_id, 0.3);
+            assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        }
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 4, 0.9);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+
+        let client_id = 2;
+        for tx_id in 5..15 {
+            let deposit_txn = Transaction::new_deposit_txn(client_id, tx_id, 0.01);
+            assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        }
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 15, 0.1);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_ok();
+        check_client!(client_records, 2, 0.0, 0.0);
+
+        client_records.set_epsilon(0.0);
+        for tx_id in 16..26 {
+            let deposit_txn = Transaction::new_deposit_txn(client_id, tx_id, 0.01);
+            assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        }
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 26, 0.1);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_err();
+    }
+
+    #[test]
+    fn it_should_build_records_from_transactions_and_collect_rejections() {
+        let (client_records, rejected) = ClientRecords::from_transactions(vec![
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 20.0),
+            Transaction::new_deposit_txn(2, 3, 5.0),
+            Transaction::new_dispute_txn(2, 3),
+            Transaction::new_deposit_txn(2, 3, 5.0),
+        ])
+        .unwrap();
+
+        check_client!(client_records, 1, 10.0, 0.0);
+        check_client!(client_records, 2, 0.0, 5.0);
+        assert_that!(rejected).is_equal_to(vec![
+            (
+                2,
+                ProcessTransactionError::InsufficientFunds(2, TransactionType::Withdrawal),
+            ),
+            (3, ProcessTransactionError::DuplicateTransaction(3)),
+        ]);
+    }
+
+    #[test]
+    fn it_should_only_block_disputes_on_a_locked_account_when_lock_freezes_all() {
+        let build = |lock_freezes_all: bool| {
+            let mut client_records = ClientRecords::new();
+            client_records.set_lock_freezes_all(lock_freezes_all);
+            for txn in [
+                Transaction::new_deposit_txn(1, 1, 10.0),
+                Transaction::new_deposit_txn(1, 2, 5.0),
+                Transaction::new_dispute_txn(1, 1),
+                Transaction::new_chargeback_txn(1, 1),
+            ] {
+                assert_that(&client_records.process_transaction(&txn)).is_ok();
+            }
+            client_records
+        };
+        let dispute_txn = Transaction::new_dispute_txn(1, 2);
+
+        let mut client_records = build(false);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 5.0);
+
+        let mut client_records = build(true);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::ClientAccountFrozen(2, TransactionType::Dispute, 1),
+        );
+        check_client!(client_records, 1, 5.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_prune_finalized_deposits_from_the_ledger() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_prune_finalized(true);
+        let client_id = 1;
+        for txn in [
+            Transaction::new_deposit_txn(client_id, 1, 10.0),
+            Transaction::new_deposit_txn(client_id, 2, 5.0),
+            Transaction::new_dispute_txn(client_id, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        assert_that!(client_records.ledger.deposits().count()).is_equal_to(2);
+
+        // A resolved deposit can still be disputed again, so it is kept.
+        let resolve_txn = Transaction::new_resolve_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
+        assert_that!(client_records.ledger.deposits().count()).is_equal_to(2);
+        check_client!(client_records, 1, 15.0, 0.0);
+
+        for txn in [
+            Transaction::new_dispute_txn(client_id, 1),
+            Transaction::new_chargeback_txn(client_id, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        assert_that!(client_records.ledger.deposits().count()).is_equal_to(1);
+        check_client!(client_records, 1, 5.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::MissingTransaction(1, 1, TransactionType::Dispute),
+        );
+
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        assert_that(&client_records.process_transaction(&deposit_txn))
+            .is_err_containing(ProcessTransactionError::DuplicateTransaction(1));
+        check_client!(client_records, 1, 5.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_collect_transactions_into_client_records() {
+        let txns = vec![
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 3.0),
+            Transaction::new_withdrawal_txn(1, 3, 30.0),
+            Transaction::new_deposit_txn(2, 4, 5.0),
+            Transaction::new_dispute_txn(2, 4),
+        ];
+        let client_records: ClientRecords = txns.into_iter().collect();
+
+        check_client!(client_records, 1, 7.0, 0.0);
+        check_client!(client_records, 2, 0.0, 5.0);
+    }
+
+    #[test]
+    fn it_should_reject_transactions_below_the_minimum_amount() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_min_transaction_amount(1.0);
+        let client_id = 1;
+
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 1.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+
+        let deposit_txn = Transaction::new_deposit_txn(client_id, 2, 0.5);
+        assert_that(&client_records.process_transaction(&deposit_txn))
+            .is_err_containing(ProcessTransactionError::AmountTooSmall(2, 0.5));
+
+        let withdrawal_txn = Transaction::new_withdrawal_txn(client_id, 3, 0.01);
+        assert_that(&client_records.process_transaction(&withdrawal_txn))
+            .is_err_containing(ProcessTransactionError::AmountTooSmall(3, 0.01));
+        check_client!(client_records, 1, 1.0, 0.0);
+
+        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 1.0);
+    }
+
+    #[test]
+    fn it_should_clone_every_process_transaction_error_variant() {
+        let errors = [
+            ProcessTransactionError::DuplicateTransaction(1),
+            ProcessTransactionError::InsufficientFunds(1, TransactionType::Withdrawal),
+            ProcessTransactionError::MissingTransaction(1, 2, TransactionType::Dispute),
+            ProcessTransactionError::InvalidTransactionState(
+                1,
+                TransactionType::Resolve,
+                TransactionType::Deposit,
+            ),
+            ProcessTransactionError::ClientAccountFrozen(1, TransactionType::Withdrawal, 2),
+            ProcessTransactionError::CannotDisputeWithdrawal(1),
+            ProcessTransactionError::HeldLimitExceeded(1, 2),
+            ProcessTransactionError::AmountTooSmall(1, 0.5),
+            ProcessTransactionError::HeldFundsInconsistent(1),
+            ProcessTransactionError::AdjustmentExceedsBalance(1, Bucket::Held),
+            ProcessTransactionError::NegativeCorrection(1),
+            ProcessTransactionError::ZeroAmount(1, TransactionType::Deposit),
+            ProcessTransactionError::SequenceExhausted,
+            ProcessTransactionError::ClientFinalized(1, TransactionType::Deposit, 2),
+            ProcessTransactionError::InvalidDisputeAmount(1, 2.0),
+            ProcessTransactionError::EventIdConflict(
+                1,
+                TransactionType::Dispute,
+                "event".to_string(),
+            ),
+            ProcessTransactionError::WithdrawalNotPending(1, TransactionType::Settle),
+            ProcessTransactionError::NegativeBalance(1, 2),
+            ProcessTransactionError::IrreversibleTransaction(1, TransactionType::Chargeback),
+            ProcessTransactionError::Internal(1, TransactionType::Dispute, "broken"),
+        ];
+
+        for error in &errors {
+            assert_that!(error.clone()).is_equal_to(error);
+        }
+    }
+
+    #[test]
+    fn it_should_classify_every_process_transaction_error_variant() {
+        let errors = [
+            (ProcessTransactionError::DuplicateTransaction(1), true),
+            (
+                ProcessTransactionError::InsufficientFunds(1, TransactionType::Withdrawal),
+                true,
+            ),
+            (
+                ProcessTransactionError::MissingTransaction(1, 2, TransactionType::Dispute),
+                true,
+            ),
+            (
+                ProcessTransactionError::InvalidTransactionState(
+                    1,
+                    TransactionType::Resolve,
+                    TransactionType::Deposit,
+                ),
+                true,
+            ),
+            (
+                ProcessTransactionError::ClientAccountFrozen(1, TransactionType::Withdrawal, 2),
+                true,
+            ),
+            (ProcessTransactionError::CannotDisputeWithdrawal(1), true),
+            (ProcessTransactionError::HeldLimitExceeded(1, 2), true),
+            (ProcessTransactionError::AmountTooSmall(1, 0.5), true),
+            (ProcessTransactionError::HeldFundsInconsistent(1), false),
+            (
+                ProcessTransactionError::AdjustmentExceedsBalance(1, Bucket::Held),
+                true,
+            ),
+            (ProcessTransactionError::NegativeCorrection(1), true),
+            (
+                ProcessTransactionError::ZeroAmount(1, TransactionType::Deposit),
+                true,
+            ),
+            (ProcessTransactionError::SequenceExhausted, false),
+            (
+                ProcessTransactionError::ClientFinalized(1, TransactionType::Deposit, 2),
+                true,
+            ),
+            (ProcessTransactionError::InvalidDisputeAmount(1, 2.0), true),
+            (
+                ProcessTransactionError::EventIdConflict(
+                    1,
+                    TransactionType::Dispute,
+                    "event".to_string(),
+                ),
+                true,
+            ),
+            (
+                ProcessTransactionError::WithdrawalNotPending(1, TransactionType::Settle),
+                true,
+            ),
+            (ProcessTransactionError::NegativeBalance(1, 2), false),
+            (
+                ProcessTransactionError::IrreversibleTransaction(1, TransactionType::Chargeback),
+                true,
+            ),
+            (
+                ProcessTransactionError::Internal(1, TransactionType::Dispute, "broken"),
+                false,
+            ),
+        ];
+
+        for (error, is_recoverable) in &errors {
+            assert_that!(error.is_recoverable()).is_equal_to(is_recoverable);
+        }
+    }
+
+    #[test]
+    fn it_should_flag_inconsistent_held_funds_on_resolve() {
+        let mut client_records = ClientRecords::new();
+        let client_id = 1;
+        for txn in [
+            Transaction::new_deposit_txn(client_id, 1, 10.0),
+            Transaction::new_dispute_txn(client_id, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        // Corrupt the state so the held funds no longer cover the open dispute.
+        client_records.records.get_mut(&1).unwrap().held_amounts = 4.0;
+
+        let resolve_txn = Transaction::new_resolve_txn(client_id, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn))
+            .is_err_containing(ProcessTransactionError::HeldFundsInconsistent(1));
+        check_client!(client_records, 1, 0.0, 4.0);
+    }
+
+    #[test]
+    fn it_should_reject_everything_for_a_sealed_account_until_it_is_unlocked() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_seal_on_chargeback(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 5.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        for txn in [
+            Transaction::new_deposit_txn(1, 3, 1.0),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_err_containing(
+                ProcessTransactionError::ClientAccountFrozen(txn.tx_id, txn.txn_type, 1),
+            );
+        }
+        check_client!(client_records, 1, 5.0, 0.0);
+
+        assert_that(&client_records.process_transaction(&Transaction::new_unlock_txn(1, 4)))
+            .is_ok();
+        assert_that(&client_records.process_transaction(&Transaction::new_deposit_txn(1, 3, 1.0)))
+            .is_ok();
+        check_client!(client_records, 1, 6.0, 0.0);
+        assert_that!(client_records.view().get(&1).unwrap().is_locked()).is_false();
+    }
+
+    #[test]
+    fn it_should_keep_accepting_deposits_on_a_locked_account_when_not_sealing() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+            Transaction::new_deposit_txn(1, 2, 3.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 3.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_move_funds_between_buckets_preserving_the_total() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 4.0),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        assert_that(&client_records.adjust(1, Bucket::Held, Bucket::Available, 1.5)).is_ok();
+        check_client!(client_records, 1, 11.5, 2.5);
+
+        assert_that(&client_records.adjust(1, Bucket::Available, Bucket::Held, 11.5)).is_ok();
+        check_client!(client_records, 1, 0.0, 14.0);
+    }
+
+    #[test]
+    fn it_should_reject_an_adjustment_larger_than_the_source_bucket() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_dispute_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        assert_that(&client_records.adjust(1, Bucket::Held, Bucket::Available, 10.5))
+            .is_err()
+            .matches(|e| {
+                Some(&ProcessTransactionError::AdjustmentExceedsBalance(
+                    1,
+                    Bucket::Held,
+                )) == e.downcast_ref::<ProcessTransactionError>()
+            });
+        check_client!(client_records, 1, 0.0, 10.0);
+    }
+
+    #[test]
+    fn it_should_never_withdraw_held_funds() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 3.0),
+            Transaction::new_dispute_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        let txn = Transaction::new_withdrawal_txn(1, 3, 5.0);
+        assert_that(&client_records.process_transaction(&txn)).is_err_containing(
+            ProcessTransactionError::InsufficientFunds(3, TransactionType::Withdrawal),
+        );
+        check_client!(client_records, 1, 3.0, 10.0);
+
+        let txn = Transaction::new_withdrawal_txn(1, 4, 3.0);
+        assert_that(&client_records.process_transaction(&txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
+    }
+
+    #[test]
+    fn it_should_diff_only_the_clients_that_changed() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(2, 2, 5.0),
+            Transaction::new_deposit_txn(3, 3, 1.0),
+            Transaction::new_dispute_txn(2, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        let prev = client_records.clone();
+
+        for txn in [
+            Transaction::new_deposit_txn(1, 4, 2.5),
+            Transaction::new_chargeback_txn(2, 2),
+            Transaction::new_deposit_txn(4, 5, 3.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        assert_that!(client_records.diff(&prev)).is_equal_to(vec![
+            ClientDelta {
+                client_id: 1,
+                d_available: 2.5,
+                d_held: 0.0,
+                d_total: 2.5,
+                locked_changed: false,
+            },
+            ClientDelta {
+                client_id: 2,
+                d_available: 0.0,
+                d_held: -5.0,
+                d_total: -5.0,
+                locked_changed: true,
+            },
+            ClientDelta {
+                client_id: 4,
+                d_available: 3.0,
+                d_held: 0.0,
+                d_total: 3.0,
+                locked_changed: false,
+            },
+        ]);
+        assert_that!(client_records.diff(&client_records)).is_empty();
+    }
+
+    #[test]
+    fn it_should_log_applied_corrections() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 4.0),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        assert_that(&client_records.apply_correction(1, 4.0, -4.0, "dispute applied twice"))
+            .is_ok();
+        check_client!(client_records, 1, 14.0, 0.0);
+
+        assert_that(&client_records.apply_correction(1, -15.0, 0.0, "overcorrection"))
+            .is_err()
+            .matches(|e| {
+                Some(&ProcessTransactionError::NegativeCorrection(1))
+                    == e.downcast_ref::<ProcessTransactionError>()
+            });
+        check_client!(client_records, 1, 14.0, 0.0);
+
+        assert_that!(client_records.corrections().to_vec()).is_equal_to(vec![Correction {
+            sequence: 1,
+            client_id: 1,
+            delta_available: 4.0,
+            delta_held: -4.0,
+            reason: "dispute applied twice".to_string(),
+        }]);
+
+        client_records.set_allow_negative_corrections(true);
+        assert_that(&client_records.apply_correction(1, -15.0, 0.0, "overcorrection")).is_ok();
+        check_client!(client_records, 1, -1.0, 0.0);
+        assert_that!(client_records.corrections()[1].sequence).is_equal_to(2);
+    }
+
+    #[test]
+    fn it_should_not_add_an_unknown_client_when_its_dispute_fails() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 1.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        for txn in [
+            Transaction::new_dispute_txn(7, 1),
+            Transaction::new_dispute_txn(7, 2),
+            Transaction::new_resolve_txn(7, 99),
+            Transaction::new_chargeback_txn(7, 99),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_err();
+        }
+
+        assert_that!(client_records.view().get(&7)).is_none();
+        assert_that!(client_records.view().len()).is_equal_to(1);
+    }
+
+    #[test]
+    fn it_should_accept_a_zero_amount_deposit_as_a_no_op_by_default() {
+        let mut client_records = ClientRecords::new();
+        let deposit_txn = Transaction::new_deposit_txn(1, 1, 0.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_reject_zero_amounts_in_strict_mode() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_strict(true);
+        assert_that(&client_records.process_transaction(&Transaction::new_deposit_txn(1, 1, 5.0)))
+            .is_ok();
+
+        for txn in [
+            Transaction::new_deposit_txn(1, 2, 0.0),
+            Transaction::new_withdrawal_txn(1, 3, 0.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn))
+                .is_err_containing(ProcessTransactionError::ZeroAmount(txn.tx_id, txn.txn_type));
+        }
+        check_client!(client_records, 1, 5.0, 0.0);
+        assert_that!(client_records.is_txn_processed((None, 2))).is_false();
+    }
+
+    #[test]
+    fn it_should_leave_balances_untouched_when_a_disputed_withdrawal_is_resolved() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_withdrawal_disputes(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 4.0),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 6.0, 0.0);
+
+        assert_that(&client_records.process_transaction(&Transaction::new_resolve_txn(1, 2)))
+            .is_ok();
+        check_client!(client_records, 1, 6.0, 0.0);
+        assert_that!(client_records.view().get(&1).unwrap().is_locked()).is_false();
+
+        let txn = Transaction::new_chargeback_txn(1, 2);
+        assert_that(&client_records.process_transaction(&txn)).is_err_containing(
+            ProcessTransactionError::InvalidTransactionState(
+                2,
+                TransactionType::Chargeback,
+                TransactionType::Resolve,
+            ),
+        );
+    }
+
+    #[test]
+    fn it_should_reverse_a_charged_back_withdrawal_and_lock_the_account() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_withdrawal_disputes(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 4.0),
+            Transaction::new_dispute_txn(1, 2),
+            Transaction::new_chargeback_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.view().get(&1).unwrap().is_locked()).is_true();
+    }
+
+    #[test]
+    fn it_should_not_add_a_new_client_when_its_duplicate_deposit_is_rejected() {
+        let mut client_records = ClientRecords::new();
+        assert_that(&client_records.process_transaction(&Transaction::new_deposit_txn(1, 1, 10.0)))
+            .is_ok();
+
+        let duplicate_txn = Transaction::new_deposit_txn(2, 1, 5.0);
+        assert_that(&client_records.process_transaction(&duplicate_txn))
+            .is_err_containing(ProcessTransactionError::DuplicateTransaction(1));
+        assert_that!(client_records.view().get(&2)).is_none();
+
+        assert_that(&client_records.process_transaction(&Transaction::new_unlock_txn(3, 2)))
+            .is_ok();
+        assert_that!(client_records.view().len()).is_equal_to(1);
+    }
+
+    #[test]
+    fn it_should_reopen_a_resolved_deposit_with_a_new_dispute() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 2.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_resolve_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 12.0, 0.0);
+        assert_that!(client_records.was_resolved(1, 1)).is_true();
+        assert_that!(client_records.was_resolved(1, 2)).is_false();
+
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 1)))
+            .is_ok();
+        check_client!(client_records, 1, 2.0, 10.0);
+        assert_that!(client_records.was_resolved(1, 1)).is_true();
+
+        assert_that(&client_records.process_transaction(&Transaction::new_chargeback_txn(1, 1)))
+            .is_ok();
+        check_client!(client_records, 1, 2.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_serialize_a_directly_constructed_client() {
+        let client = Client::new_with_balances(7, 1.23456, -0.5, true);
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(&client).unwrap();
+        assert_that!(String::from_utf8(wtr.into_inner().unwrap()).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n7,1.2346,-0.5000,0.7346,true\n".to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_compare_clients_within_an_epsilon() {
+        let client = Client::new_with_balances(1, 10.0, 2.0, false);
+        let nearby = Client::new_with_balances(1, 10.0 + 1e-6, 2.0 - 1e-6, false);
+
+        assert_that!(client == nearby).is_false();
+        assert_that!(client.approx_eq(&nearby, 1e-5)).is_true();
+        assert_that!(client.approx_eq(&nearby, 1e-7)).is_false();
+        assert_that!(client.approx_eq(&Client::new_with_balances(1, 10.0, 2.0, true), 1.0))
+            .is_false();
+        assert_that!(client.approx_eq(&Client::new_with_balances(2, 10.0, 2.0, false), 1.0))
+            .is_false();
+    }
+
+    #[test]
+    fn it_should_reject_transactions_for_a_finalized_client() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(2, 2, 5.0),
+            Transaction::new_finalize_txn(1, 3),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        let finalized = client_records.drain_finalized();
+        assert_that!(finalized.len()).is_equal_to(1);
+        assert_that!(finalized[0].client_id).is_equal_to(1);
+        assert_that!(client_records.drain_finalized()).is_empty();
+        assert_that!(client_records.is_finalized(1)).is_true();
+        assert_that!(client_records.is_finalized(2)).is_false();
+
+        let txn = Transaction::new_withdrawal_txn(1, 4, 1.0);
+        assert_that(&client_records.process_transaction(&txn)).is_err_containing(
+            ProcessTransactionError::ClientFinalized(4, TransactionType::Withdrawal, 1),
+        );
+        check_client!(client_records, 1, 10.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_track_concurrent_disputes_on_different_deposits_independently() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 20.0),
+            Transaction::new_deposit_txn(1, 3, 30.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_dispute_txn(1, 2),
+            Transaction::new_dispute_txn(1, 3),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 0.0, 60.0);
+
+        let resolve_txn = Transaction::new_resolve_txn(1, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 50.0);
+
+        let chargeback_txn = Transaction::new_chargeback_txn(1, 2);
+        assert_that(&client_records.process_transaction(&chargeback_txn)).is_ok();
+        check_client!(client_records, 1, 10.0, 30.0);
+        assert_that!(client_records.view().get(&1).unwrap().is_locked()).is_true();
+
+        // The third deposit is still disputed and can be resolved on its own.
+        let resolve_txn = Transaction::new_resolve_txn(1, 3);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
+        check_client!(client_records, 1, 40.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_only_release_the_disputed_part_of_a_deposit_on_resolve() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 100.0),
+            Transaction::new(TransactionType::Dispute, 1, 1, Some(40.0)),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 60.0, 40.0);
+
+        let resolve_txn = Transaction::new_resolve_txn(1, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
+        check_client!(client_records, 1, 100.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_reject_a_dispute_for_more_than_the_deposit() {
+        let mut client_records = ClientRecords::new();
+        let deposit_txn = Transaction::new_deposit_txn(1, 1, 100.0);
+        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+
+        let dispute_txn = Transaction::new(TransactionType::Dispute, 1, 1, Some(100.5));
+        assert_that(&client_records.process_transaction(&dispute_txn))
+            .is_err_containing(ProcessTransactionError::InvalidDisputeAmount(1, 100.5));
+        check_client!(client_records, 1, 100.0, 0.0);
+    }
+
+    #[test]
+    fn it_should_report_whether_a_tx_id_has_been_processed() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 50.0),
+        ] {
+            let _ = client_records.process_transaction(&txn);
+        }
+
+        assert_that!(client_records.is_processed(1, 1)).is_true();
+        // A rejected withdrawal does not use up its tx id.
+        assert_that!(client_records.is_processed(1, 2)).is_false();
+        assert_that!(client_records.is_processed(1, 3)).is_false();
+    }
+
+    #[test]
+    fn it_should_keep_transactions_rejected_by_a_frozen_account_as_dead_letters() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 5.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        let withdrawal_txn = Transaction::new_withdrawal_txn(1, 3, 1.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_err();
+        // Rejections for any other reason are not dead letters.
+        let withdrawal_txn = Transaction::new_withdrawal_txn(2, 4, 1.0);
+        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_err();
+
+        let dead_letters = client_records.dead_letters();
+        assert_that!(dead_letters.len()).is_equal_to(1);
+        assert_that!(dead_letters[0].tx_id).is_equal_to(3);
+        assert_that!(dead_letters[0].txn_type).is_equal_to(TransactionType::Withdrawal);
+    }
+
+    #[test]
+    fn it_should_consume_the_records_into_clients_ordered_by_id() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(3, 1, 1.0),
+            Transaction::new_deposit_txn(1, 2, 2.0),
+            Transaction::new_deposit_txn(2, 3, 3.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        let expected = client_records.view().get(&1).cloned();
+
+        let clients = client_records.into_clients();
+
+        assert_that!(clients
+            .iter()
+            .map(|c| c.client_id)
+            .collect::<Vec<ClientId>>())
+        .is_equal_to(vec![1, 2, 3]);
+        assert_that!(clients.first().cloned()).is_equal_to(expected);
+    }
+
+    #[test]
+    fn it_should_ignore_a_replayed_dispute_event() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_dispute_txn(1, 1).with_event_id("evt-1"),
+            Transaction::new_dispute_txn(1, 1).with_event_id("evt-1"),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 0.0, 10.0);
+        assert_that!(client_records.view().get(&1).unwrap().had_errors).is_false();
+    }
+
+    #[test]
+    fn it_should_apply_the_state_machine_to_a_conflicting_dispute_event() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 5.0),
+            Transaction::new_dispute_txn(1, 1).with_event_id("evt-1"),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        // A different event disputing the same deposit is a second dispute.
+        let dispute_txn = Transaction::new_dispute_txn(1, 1).with_event_id("evt-2");
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::InvalidTransactionState(
+                1,
+                TransactionType::Dispute,
+                TransactionType::Dispute,
+            ),
+        );
+
+        // Reusing an event id for a different transaction is rejected.
+        let dispute_txn = Transaction::new_dispute_txn(1, 2).with_event_id("evt-1");
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_err_containing(
+            ProcessTransactionError::EventIdConflict(
+                2,
+                TransactionType::Dispute,
+                "evt-1".to_string(),
+            ),
+        );
+        check_client!(client_records, 1, 5.0, 10.0);
+    }
+
+    #[test]
+    fn it_should_reject_a_deposit_built_without_an_amount_in_strict_mode() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_strict(true);
+        let txn = Transaction::builder()
+            .txn_type(TransactionType::Deposit)
+            .client(1)
+            .tx(1)
+            .build();
+
+        assert_that(&client_records.process_transaction(&txn)).is_err_containing(
+            ProcessTransactionError::ZeroAmount(1, TransactionType::Deposit),
+        );
+        assert_that!(client_records.view().get(&1)).is_none();
     }
 
     #[test]
-    fn it_should_fail_process_the_same_deposit_twice_by_txn_id() {
+    fn it_should_record_why_an_account_was_locked() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        client_records.set_withdrawal_disputes(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(2, 2, 10.0),
+            Transaction::new_withdrawal_txn(2, 3, 5.0),
+            Transaction::new_deposit_txn(3, 4, 10.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        assert_that!(client_records.view().get(&1).unwrap().lock_reason()).is_none();
+
+        for txn in [
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+            Transaction::new_dispute_txn(2, 3),
+            Transaction::new_chargeback_txn(2, 3),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+
+        assert_that!(client_records.view().get(&1).unwrap().lock_reason())
+            .is_equal_to(Some(LockReason::Chargeback));
+        assert_that!(client_records.view().get(&2).unwrap().lock_reason())
+            .is_equal_to(Some(LockReason::WithdrawalChargeback));
+        assert_that!(client_records.view().get(&3).unwrap().lock_reason()).is_none();
+
+        let unlock_txn = Transaction::new_unlock_txn(1, 5);
+        assert_that(&client_records.process_transaction(&unlock_txn)).is_ok();
+        assert_that!(client_records.view().get(&1).unwrap().lock_reason()).is_none();
+    }
 
+    #[test]
+    fn it_should_reject_a_resolve_or_chargeback_without_an_open_dispute() {
+        let mut client_records = ClientRecords::new();
+        let deposit_txn = Transaction::new_deposit_txn(1, 1, 10.0);
         assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
 
-        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 1, 123.0);
+        for txn in [
+            Transaction::new_resolve_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+        ] {
+            let result = client_records.process_transaction(&txn);
+            assert_that(&result).is_err_containing(
+                ProcessTransactionError::InvalidTransactionState(
+                    1,
+                    txn.txn_type,
+                    TransactionType::Deposit,
+                ),
+            );
+            assert_that!(result.unwrap_err().to_string()).contains("no open dispute");
+        }
+        check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.view().get(&1).unwrap().is_locked()).is_false();
+    }
 
-        assert_that(&client_records.process_transaction(&deposit_txn_2))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::DuplicateTransaction(1))
-                    == e.downcast_ref::<ProcessTransactionError>()
-            });
+    #[test]
+    fn it_should_ignore_a_resolve_or_chargeback_without_an_open_dispute_when_lenient() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_ignore_missing_disputes(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_resolve_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
         check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.view().get(&1).unwrap().is_locked()).is_false();
+        assert_that!(client_records.view().get(&1).unwrap().had_errors).is_false();
+
+        // The deposit can still be disputed and charged back afterwards.
+        for txn in [
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 1, 0.0, 0.0);
+
+        // Settling a dispute that was already charged back is still an error.
+        let resolve_txn = Transaction::new_resolve_txn(1, 1);
+        assert_that(&client_records.process_transaction(&resolve_txn)).is_err();
     }
 
     #[test]
-    fn it_should_fail_process_the_same_withdrawal_twice_by_txn_id() {
+    fn it_should_dispute_exactly_the_available_amount_despite_float_error() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 0.35),
+            Transaction::new_deposit_txn(1, 2, 0.3),
+            Transaction::new_withdrawal_txn(1, 3, 0.35),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        // In f32, 0.35 + 0.3 - 0.35 comes out just below 0.3.
+        assert_that!(client_records.view().get(&1).unwrap().available_amounts < 0.3).is_true();
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
+        let dispute_txn = Transaction::new_dispute_txn(1, 2);
+        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        check_client!(client_records, 1, 0.0, 0.3);
+    }
 
-        let withdrawal_txn_1 = Transaction::new_withdrawal_txn(client_id, 2, 1.0);
+    #[test]
+    fn it_should_process_the_same_with_capacity_as_without() {
+        let txns = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 2.5),
+            Transaction::new_deposit_txn(2, 3, 5.0),
+            Transaction::new_dispute_txn(2, 3),
+            Transaction::new_withdrawal_txn(2, 4, 1.0),
+        ];
+        let mut client_records = ClientRecords::with_capacity(2, txns.len());
+        let mut expected = ClientRecords::new();
+        for txn in &txns {
+            assert_that!(client_records.process_transaction(txn).is_ok())
+                .is_equal_to(expected.process_transaction(txn).is_ok());
+        }
 
-        assert_that(&client_records.process_transaction(&withdrawal_txn_1)).is_ok();
-        check_client!(client_records, 1, 9.0, 0.0);
+        assert_that!(client_records.records.capacity() >= 2).is_true();
+        assert_that!(client_records.view()).is_equal_to(expected.view());
+    }
 
-        let withdrawal_txn_2 = Transaction::new_withdrawal_txn(client_id, 2, 5.0);
+    #[test]
+    fn it_should_return_the_error_variant_without_downcasting() {
+        let mut client_records = ClientRecords::new();
+        client_records
+            .process_transaction(&Transaction::new_deposit_txn(1, 1, 5.0))
+            .unwrap();
+
+        match client_records.process_transaction(&Transaction::new_withdrawal_txn(1, 2, 10.0)) {
+            Err(ProcessTransactionError::InsufficientFunds(tx_id, txn_type)) => {
+                assert_that!(tx_id).is_equal_to(2);
+                assert_that!(txn_type).is_equal_to(TransactionType::Withdrawal);
+            }
+            other => panic!("expected insufficient funds, got: {:?}", other),
+        }
 
-        assert_that(&client_records.process_transaction(&withdrawal_txn_2))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::DuplicateTransaction(2))
-                    == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 9.0, 0.0);
+        let err = client_records
+            .process_transaction(&Transaction::new_dispute_txn(1, 3))
+            .unwrap_err();
+        assert_that!(err).is_equal_to(ProcessTransactionError::MissingTransaction(
+            3,
+            1,
+            TransactionType::Dispute,
+        ));
+        assert_that!(err.is_recoverable()).is_true();
     }
 
     #[test]
-    fn it_should_be_able_to_dispute_a_deposit_txn() {
+    fn it_should_hold_a_pending_withdrawal_until_it_is_settled() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        client_records.set_pending_withdrawals(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 4.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        let client = &client_records.view()[&1];
+        assert_that!(client.available_amounts).is_equal_to(6.0);
+        assert_that!(client.pending_amounts).is_equal_to(4.0);
+        assert_that!(client.total_amounts()).is_equal_to(10.0);
+
+        assert_that(&client_records.process_transaction(&Transaction::new_settle_txn(1, 2)))
+            .is_ok();
+        let client = &client_records.view()[&1];
+        assert_that!(client.available_amounts).is_equal_to(6.0);
+        assert_that!(client.pending_amounts).is_equal_to(0.0);
+        assert_that!(client.total_amounts()).is_equal_to(6.0);
+
+        assert_that(&client_records.process_transaction(&Transaction::new_cancel_txn(1, 2)))
+            .is_err_containing(ProcessTransactionError::WithdrawalNotPending(
+                2,
+                TransactionType::Cancel,
+            ));
+        check_client!(client_records, 1, 6.0, 0.0);
+    }
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+    #[test]
+    fn it_should_return_a_cancelled_pending_withdrawal_to_available() {
+        let mut client_records = ClientRecords::new();
+        client_records.set_pending_withdrawals(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 4.0),
+            Transaction::new_cancel_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
         check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.view()[&1].pending_amounts).is_equal_to(0.0);
+
+        assert_that(&client_records.process_transaction(&Transaction::new_settle_txn(1, 2)))
+            .is_err_containing(ProcessTransactionError::WithdrawalNotPending(
+                2,
+                TransactionType::Settle,
+            ));
+        assert_that(&client_records.process_transaction(&Transaction::new_settle_txn(2, 2)))
+            .is_err_containing(ProcessTransactionError::MissingTransaction(
+                2,
+                2,
+                TransactionType::Settle,
+            ));
 
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
-        check_client!(client_records, 1, 0.0, 10.0);
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 4.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        assert_that(&client_records.process_transaction(&Transaction::new_cancel_txn(1, 2)))
+            .is_err_containing(ProcessTransactionError::WithdrawalNotPending(
+                2,
+                TransactionType::Cancel,
+            ));
+        check_client!(client_records, 1, 6.0, 0.0);
     }
 
     #[test]
-    fn it_should_fail_to_dispute_the_same_txn_twice() {
+    fn it_should_fail_fatally_when_an_overdraft_leaves_a_negative_total_in_strict_balance_mode() {
+        let txns = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 15.0),
+        ];
+
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        client_records.set_overdraft_limit(5.0);
+        for txn in &txns {
+            assert_that(&client_records.process_transaction(txn)).is_ok();
+        }
+        check_client!(client_records, 1, -5.0, 0.0);
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
+        let mut client_records = ClientRecords::new();
+        client_records.set_overdraft_limit(5.0);
+        client_records.set_strict_balance(true);
+        assert_that(&client_records.process_transaction(&txns[0])).is_ok();
+        let result = client_records.process_transaction(&txns[1]);
+        assert_that(&result).is_err_containing(ProcessTransactionError::NegativeBalance(2, 1));
+        assert_that!(result.unwrap_err().is_recoverable()).is_false();
         check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.is_processed(1, 2)).is_false();
+    }
 
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
-        check_client!(client_records, 1, 0.0, 10.0);
+    #[cfg(feature = "u32-client-ids")]
+    #[test]
+    fn it_should_process_client_ids_above_the_u16_range() {
+        let csv = "type,client,tx,amount\ndeposit,100000,1,10.0\nwithdrawal,100000,2,2.5\n";
+        let mut client_records = ClientRecords::new();
+        for txn in parse_csv(csv.as_bytes()).unwrap() {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        check_client!(client_records, 100_000, 7.5, 0.0);
 
-        assert_that(&client_records.process_transaction(&dispute_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::InvalidTransactionState(
-                    1,
-                    TransactionType::Dispute,
-                    TransactionType::Dispute,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 0.0, 10.0);
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &OutputFormat::default()).unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n100000,7.5000,0.0000,7.5000,false\n".to_string(),
+        );
     }
 
     #[test]
-    fn it_should_fail_to_dispute_a_txn_that_doesnt_exist() {
+    fn it_should_handle_a_dispute_resolve_and_redispute_in_one_batch() {
         let mut client_records = ClientRecords::new();
+        let rejected = client_records
+            .process_transactions(&[
+                Transaction::new_deposit_txn(1, 1, 10.0),
+                Transaction::new_deposit_txn(1, 2, 5.0),
+                Transaction::new_dispute_txn(1, 1),
+                Transaction::new_resolve_txn(1, 1),
+                Transaction::new_dispute_txn(1, 1),
+                Transaction::new_resolve_txn(1, 2),
+            ])
+            .unwrap();
+
+        assert_that!(rejected).is_equal_to(vec![(
+            2,
+            ProcessTransactionError::InvalidTransactionState(
+                2,
+                TransactionType::Resolve,
+                TransactionType::Deposit,
+            ),
+        )]);
+        check_client!(client_records, 1, 5.0, 10.0);
+        assert_that!(client_records.was_resolved(1, 1)).is_true();
+    }
 
-        let dispute_txn = Transaction::new_dispute_txn(1, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::MissingTransaction(
-                    1,
-                    1,
-                    TransactionType::Dispute,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
+    #[test]
+    fn it_should_catch_a_corrupted_ledger_in_the_integrity_check() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 3.0),
+            Transaction::new_withdrawal_txn(1, 4, 2.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_deposit_txn(2, 3, 5.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        assert_that(&client_records.validate_ledger_integrity()).is_ok();
+
+        let mut corrupted = client_records.clone();
+        corrupted.records.get_mut(&1).unwrap().held_amounts = 4.0;
+        assert_that!(corrupted
+            .validate_ledger_integrity()
+            .unwrap_err()
+            .to_string())
+        .is_equal_to("client: 1 holds 4.0000 but has 10.0000 in open disputes".to_string());
+
+        let mut corrupted = client_records.clone();
+        corrupted.records.get_mut(&2).unwrap().available_amounts = -1.0;
+        assert_that!(corrupted
+            .validate_ledger_integrity()
+            .unwrap_err()
+            .to_string())
+        .is_equal_to("client: 2 has a total of -1.0000, below the minimum of 0.0000".to_string());
+
+        let mut corrupted = client_records.clone();
+        corrupted.records.remove(&2);
+        assert_that!(corrupted
+            .validate_ledger_integrity()
+            .unwrap_err()
+            .to_string())
+        .is_equal_to("deposit: \"3\" belongs to client: 2 which has no record".to_string());
     }
 
     #[test]
-    fn it_should_fail_to_dispute_a_txn_that_doesnt_exist_for_the_client() {
+    fn it_should_flag_clients_with_a_high_held_ratio() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 8.0),
+            Transaction::new_deposit_txn(1, 2, 2.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_deposit_txn(2, 3, 9.0),
+            Transaction::new_deposit_txn(2, 4, 1.0),
+            Transaction::new_dispute_txn(2, 4),
+            Transaction::new_deposit_txn(3, 5, 0.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
+        assert_that!(client_records.view()[&1].held_ratio()).is_equal_to(0.8);
+        assert_that!(client_records.view()[&2].held_ratio()).is_equal_to(0.1);
+        assert_that!(client_records.view()[&3].held_ratio()).is_equal_to(0.0);
+        let high_held: Vec<ClientId> = client_records
+            .high_held(0.5)
+            .iter()
+            .map(|client| client.client_id)
+            .collect();
+        assert_that!(high_held).is_equal_to(vec![1]);
+    }
 
-        let dispute_txn = Transaction::new_dispute_txn(2, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::MissingTransaction(
-                    1,
-                    2,
-                    TransactionType::Dispute,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 10.0, 0.0);
+    #[test]
+    fn it_should_move_funds_between_a_clients_buckets() {
+        let mut client = Client::new(1);
+        client.apply_deposit(10.0);
+
+        assert_that!(client.hold(4.0, DEFAULT_EPSILON)).is_ok();
+        assert_that!(client.release(1.0, DEFAULT_EPSILON)).is_ok();
+        assert_that!(client.charge_back(3.0, DEFAULT_EPSILON)).is_ok();
+        assert_that!(client.apply_withdrawal(7.0, 0.0, DEFAULT_EPSILON)).is_ok();
+
+        assert_that!(client.available_amounts).is_equal_to(0.0);
+        assert_that!(client.held_amounts).is_equal_to(0.0);
+        assert_that!(client.lock_reason()).is_equal_to(Some(LockReason::Chargeback));
     }
 
     #[test]
-    fn it_should_fail_to_dispute_a_txn_where_funds_are_insufficient() {
-        let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
+    fn it_should_leave_a_client_untouched_when_a_balance_operation_is_refused() {
+        let mut client = Client::new_with_balances(1, 5.0, 2.0, false);
+        let before = client.clone();
+
+        assert_that!(client.apply_withdrawal(6.0, 0.0, DEFAULT_EPSILON))
+            .is_err_containing(BalanceError::InsufficientAvailable);
+        assert_that!(client.hold(5.5, DEFAULT_EPSILON))
+            .is_err_containing(BalanceError::InsufficientAvailable);
+        assert_that!(client.release(3.0, DEFAULT_EPSILON))
+            .is_err_containing(BalanceError::InsufficientHeld);
+        assert_that!(client.charge_back(3.0, DEFAULT_EPSILON))
+            .is_err_containing(BalanceError::InsufficientHeld);
+        assert_that!(client).is_equal_to(before);
+
+        assert_that!(client.apply_withdrawal(6.0, 1.0, DEFAULT_EPSILON)).is_ok();
+        assert_that!(client.available_amounts).is_equal_to(-1.0);
+    }
 
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
+    #[test]
+    fn it_should_keep_a_snapshot_unchanged_when_the_records_change() {
+        let records = std::sync::RwLock::new(ClientRecords::new());
+        for txn in [
+            Transaction::new_deposit_txn(2, 1, 5.0),
+            Transaction::new_deposit_txn(1, 2, 10.0),
+        ] {
+            assert_that(&records.write().unwrap().process_transaction(&txn)).is_ok();
+        }
 
-        let withdraw_txn = Transaction::new_withdrawal_txn(client_id, 2, 5.0);
-        assert_that(&client_records.process_transaction(&withdraw_txn)).is_ok();
-        check_client!(client_records, 1, 5.0, 0.0);
+        let snapshot = records.read().unwrap().snapshot();
+        let reader = std::thread::spawn(move || snapshot);
+        for txn in [
+            Transaction::new_withdrawal_txn(1, 3, 4.0),
+            Transaction::new_dispute_txn(2, 1),
+            Transaction::new_deposit_txn(3, 4, 1.0),
+        ] {
+            assert_that(&records.write().unwrap().process_transaction(&txn)).is_ok();
+        }
+        let snapshot = reader.join().unwrap();
 
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::InsufficientFunds(
-                    1,
-                    TransactionType::Dispute,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 5.0, 0.0);
+        assert_that!(snapshot).is_equal_to(vec![
+            Client::new_with_balances(1, 10.0, 0.0, false),
+            Client::new_with_balances(2, 5.0, 0.0, false),
+        ]);
+        assert_that!(records.read().unwrap().view().len()).is_equal_to(3);
     }
 
     #[test]
-    fn it_should_be_able_to_resolve_a_disputed_txn() {
+    fn it_should_dispute_a_withdrawn_and_redeposited_deposit_only_by_current_available() {
+        let txns = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 10.0),
+            Transaction::new_deposit_txn(1, 3, 10.0),
+        ];
+
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
+        for txn in &txns {
+            assert_that(&client_records.process_transaction(txn)).is_ok();
+        }
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 1)))
+            .is_ok();
         check_client!(client_records, 1, 0.0, 10.0);
 
-        let resolve_txn = Transaction::new_resolve_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
+        let mut client_records = ClientRecords::new();
+        client_records.set_dispute_policy(DisputePolicy::OriginalFunds);
+        for txn in &txns {
+            assert_that(&client_records.process_transaction(txn)).is_ok();
+        }
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 1)))
+            .is_err_containing(ProcessTransactionError::InsufficientFunds(
+                1,
+                TransactionType::Dispute,
+            ));
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 3)))
+            .is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
     }
 
     #[test]
-    fn it_should_fail_to_resolve_a_txn_that_doesnt_exist() {
+    fn it_should_not_draw_withdrawals_from_removed_deposits_for_original_funds() {
         let mut client_records = ClientRecords::new();
+        client_records.set_dispute_policy(DisputePolicy::OriginalFunds);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_void_txn(1, 1),
+            Transaction::new_deposit_txn(1, 2, 10.0),
+            Transaction::new_withdrawal_txn(1, 3, 10.0),
+            Transaction::new_deposit_txn(1, 4, 10.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
 
-        let resolve_txn = Transaction::new_resolve_txn(1, 1);
-        assert_that(&client_records.process_transaction(&resolve_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::MissingTransaction(
-                    1,
-                    1,
-                    TransactionType::Resolve,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
+        // The voided deposit's funds are gone, so the withdrawal drew from tx 2.
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 2)))
+            .is_err_containing(ProcessTransactionError::InsufficientFunds(
+                2,
+                TransactionType::Dispute,
+            ));
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 4)))
+            .is_ok();
+        check_client!(client_records, 1, 0.0, 10.0);
     }
 
     #[test]
-    fn it_should_be_able_to_chargeback_a_disputed_transaction() {
+    fn it_should_draw_withdrawals_from_the_oldest_deposits_first_for_original_funds() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
-        check_client!(client_records, 1, 0.0, 10.0);
+        client_records.set_dispute_policy(DisputePolicy::OriginalFunds);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 10.0),
+            Transaction::new_withdrawal_txn(1, 3, 14.0),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
 
-        let chargeback_txn = Transaction::new_chargeback_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&chargeback_txn)).is_ok();
-        check_client!(client_records, 1, 0.0, 0.0);
-        assert_that!(client_records.view().get(&1))
-            .is_some()
-            .map(|c| &c.is_locked)
-            .is_equal_to(true);
+        // The withdrawal took all of the first deposit and 4 of the second.
+        let partial_dispute = Transaction::builder()
+            .txn_type(TransactionType::Dispute)
+            .client(1)
+            .tx(2)
+            .amount(6.5)
+            .build();
+        assert_that(&client_records.process_transaction(&partial_dispute)).is_err();
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 1)))
+            .is_err();
+        let partial_dispute = Transaction::builder()
+            .txn_type(TransactionType::Dispute)
+            .client(1)
+            .tx(2)
+            .amount(6.0)
+            .build();
+        assert_that(&client_records.process_transaction(&partial_dispute)).is_ok();
+        check_client!(client_records, 1, 0.0, 6.0);
     }
 
     #[test]
-    fn it_should_fail_to_chargeback_a_txn_that_doesnt_exist() {
+    fn it_should_reverse_a_partially_withdrawn_deposit_into_a_negative_balance() {
         let mut client_records = ClientRecords::new();
+        client_records.set_strict_balance(true);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 4.0),
+            Transaction::new_reversal_txn(1, 1),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
 
-        let chargeback_txn = Transaction::new_chargeback_txn(1, 1);
-        assert_that(&client_records.process_transaction(&chargeback_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::MissingTransaction(
-                    1,
-                    1,
-                    TransactionType::Chargeback,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
+        check_client!(client_records, 1, -4.0, 0.0);
+        assert_that(&client_records.validate_ledger_integrity()).is_ok();
+        assert_that(&client_records.process_transaction(&Transaction::new_dispute_txn(1, 1)))
+            .is_err_containing(ProcessTransactionError::InvalidTransactionState(
+                1,
+                TransactionType::Dispute,
+                TransactionType::Reversal,
+            ));
+        assert_that(&client_records.process_transaction(&Transaction::new_reversal_txn(1, 1)))
+            .is_err();
+        check_client!(client_records, 1, -4.0, 0.0);
     }
 
     #[test]
-    fn it_should_fail_to_chargeback_a_txn_where_it_has_already_been_resolved() {
+    fn it_should_only_reverse_a_deposit_without_an_open_dispute() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
-
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
-
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
-        check_client!(client_records, 1, 0.0, 10.0);
-
-        let resolve_txn = Transaction::new_resolve_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&resolve_txn)).is_ok();
-        check_client!(client_records, 1, 10.0, 0.0);
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 5.0),
+            Transaction::new_withdrawal_txn(1, 3, 8.0),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
 
-        let chargeback_txn = Transaction::new_chargeback_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&chargeback_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::InvalidTransactionState(
-                    1,
-                    TransactionType::Chargeback,
-                    TransactionType::Resolve,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
-        check_client!(client_records, 1, 10.0, 0.0);
+        assert_that(&client_records.process_transaction(&Transaction::new_reversal_txn(1, 2)))
+            .is_err_containing(ProcessTransactionError::InvalidTransactionState(
+                2,
+                TransactionType::Reversal,
+                TransactionType::Dispute,
+            ));
+        assert_that(&client_records.process_transaction(&Transaction::new_resolve_txn(1, 2)))
+            .is_ok();
+        assert_that(&client_records.process_transaction(&Transaction::new_reversal_txn(1, 2)))
+            .is_ok();
+        check_client!(client_records, 1, 2.0, 0.0);
     }
 
     #[test]
-    fn it_should_fail_to_withdraw_if_client_account_is_locked() {
+    fn it_should_return_an_internal_error_instead_of_panicking() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-        let dispute_txn = Transaction::new_dispute_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&dispute_txn)).is_ok();
-        let chargeback_txn = Transaction::new_chargeback_txn(client_id, 1);
-        assert_that(&client_records.process_transaction(&chargeback_txn)).is_ok();
+        let dispute = Transaction::new_dispute_txn(1, 9);
+
+        assert_that(&client_records.apply_withdrawal_dispute(&dispute, (None, 9)))
+            .is_err_containing(ProcessTransactionError::Internal(
+                9,
+                TransactionType::Dispute,
+                "the disputed withdrawal does not exist",
+            ));
+    }
 
-        check_client!(client_records, 1, 0.0, 0.0);
+    #[test]
+    fn it_should_not_panic_on_any_sequence_of_transaction_types() {
+        let amounts = [None, Some(0.0), Some(-1.0), Some(2.5), Some(f32::MAX)];
+        for enabled in [false, true] {
+            let mut client_records = ClientRecords::new();
+            client_records.set_withdrawal_disputes(enabled);
+            client_records.set_pending_withdrawals(enabled);
+            client_records.set_prune_finalized(enabled);
+            client_records.set_seal_on_chargeback(enabled);
+            for (i, txn_type) in TransactionType::ALL.iter().cycle().take(500).enumerate() {
+                let txn = Transaction::new(
+                    *txn_type,
+                    (i % 3) as ClientId,
+                    (i % 17) as u32,
+                    amounts[i % amounts.len()],
+                );
+                let _ = client_records.process_transaction(&txn);
+            }
+        }
+    }
 
-        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 2, 5.0);
-        assert_that(&client_records.process_transaction(&deposit_txn_2)).is_ok();
+    #[test]
+    fn it_should_skip_an_identical_repeat_only_when_deduping_across_files() {
+        let first_file = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 2.5),
+        ];
+        let second_file = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 2.5),
+            Transaction::new_deposit_txn(1, 3, 1.0),
+        ];
 
-        let withdrawal_txn = Transaction::new_withdrawal_txn(1, 3, 2.0);
-        assert_that(&client_records.process_transaction(&withdrawal_txn))
-            .is_err()
-            .matches(|e| {
-                Some(&ProcessTransactionError::ClientAccountFrozen(
-                    3,
-                    TransactionType::Withdrawal,
-                    1,
-                )) == e.downcast_ref::<ProcessTransactionError>()
-            });
+        let mut client_records = ClientRecords::new();
+        client_records.set_dedupe_across_files(true);
+        let rejected = client_records
+            .process_transactions(first_file.iter().chain(&second_file))
+            .unwrap();
+        assert_that!(rejected).is_empty();
+        check_client!(client_records, 1, 8.5, 0.0);
+
+        let mut client_records = ClientRecords::new();
+        let rejected = client_records
+            .process_transactions(first_file.iter().chain(&second_file))
+            .unwrap();
+        assert_that!(rejected).is_equal_to(vec![
+            (1, ProcessTransactionError::DuplicateTransaction(1)),
+            (2, ProcessTransactionError::DuplicateTransaction(2)),
+        ]);
+        check_client!(client_records, 1, 8.5, 0.0);
     }
 
     #[test]
-    fn it_should_ignore_failed_withdrawals_from_duplicate_tx_id_checks() {
+    fn it_should_reject_a_conflicting_repeat_when_deduping_across_files() {
         let mut client_records = ClientRecords::new();
-        let client_id = 1;
-        let deposit_txn = Transaction::new_deposit_txn(client_id, 1, 10.0);
-        assert_that(&client_records.process_transaction(&deposit_txn)).is_ok();
-
-        let withdrawal_txn = Transaction::new_withdrawal_txn(2, 2, 2.0);
-        assert_that(&client_records.process_transaction(&withdrawal_txn)).is_err();
+        client_records.set_dedupe_across_files(true);
+        let rejected = client_records
+            .process_transactions([
+                Transaction::new_deposit_txn(1, 1, 10.0),
+                Transaction::new_deposit_txn(1, 2, 5.0),
+                Transaction::new_deposit_txn(1, 1, 12.0),
+                Transaction::new_withdrawal_txn(1, 2, 5.0),
+                Transaction::new_deposit_txn(2, 1, 10.0),
+            ])
+            .unwrap();
+
+        assert_that!(rejected).is_equal_to(vec![
+            (1, ProcessTransactionError::DuplicateTransaction(1)),
+            (2, ProcessTransactionError::DuplicateTransaction(2)),
+            (1, ProcessTransactionError::DuplicateTransaction(1)),
+        ]);
+        check_client!(client_records, 1, 15.0, 0.0);
+        assert_that!(client_records.view().get(&2)).is_none();
+    }
 
-        let deposit_txn_2 = Transaction::new_deposit_txn(client_id, 2, 10.0);
-        assert_that(&client_records.process_transaction(&deposit_txn_2)).is_ok();
+    #[test]
+    fn it_should_count_clients_into_balance_buckets() {
+        let mut client_records = ClientRecords::new();
+        let balances = [(1, 5.0), (2, 10.0), (3, 99.5), (4, 100.0), (5, 2500.0)];
+        for (tx_id, (client_id, amount)) in (1..).zip(balances) {
+            let txn = Transaction::new_deposit_txn(client_id, tx_id, amount);
+            assert_that(&client_records.process_transaction(&txn)).is_ok();
+        }
+        let txn = Transaction::new_withdrawal_txn(1, 6, 5.0);
+        assert_that(&client_records.process_transaction(&txn)).is_ok();
+
+        assert_that!(client_records.balance_histogram(&[0.0, 10.0, 100.0, 1000.0]))
+            .is_equal_to(vec![(0.0, 1), (10.0, 2), (100.0, 1), (1000.0, 1)]);
+        // Client 1 has nothing left, so it falls below the first bucket.
+        assert_that!(client_records.balance_histogram(&[1.0, 1000.0]))
+            .is_equal_to(vec![(1.0, 3), (1000.0, 1)]);
+        assert_that!(client_records.balance_histogram(&[])).is_empty();
     }
 }
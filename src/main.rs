@@ -1,72 +1,699 @@
-use anyhow::Context;
-use csv::Trim;
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use chrono_tz::Tz;
 use mysterious_unnamed_rust_project::*;
 use std::{
-    fs::File,
-    io::{self, BufReader, Read},
+    fs::{self, File},
+    io::{self, BufReader, Write},
+    path::Path,
 };
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use thiserror::Error;
 
-fn parse_csv(csv: impl Read) -> anyhow::Result<Vec<Transaction>> {
-    let mut reader = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .trim(Trim::All)
-        .from_reader(csv);
+fn main() -> anyhow::Result<()> {
+    let matches = Command::new("MysteriousUnnamedRustProject")
+        .args(shared_args())
+        .subcommand(
+            Command::new("process")
+                .about("process the transactions and print the client table (the default)")
+                .args(shared_args()),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("process the transactions without printing any output, reporting every rejected transaction")
+                .args(shared_args()),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("process the transactions and print aggregate totals across all clients")
+                .args(shared_args()),
+        )
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .get_matches();
 
-    let mut transactions = vec![];
-    for result in reader.deserialize() {
-        let txn: Transaction = result.with_context(|| "failed to parse transaction")?;
-        transactions.push(txn);
+    let result = match matches.subcommand() {
+        Some(("validate", sub_matches)) => validate(sub_matches),
+        Some(("stats", sub_matches)) => stats(sub_matches),
+        Some(("process", sub_matches)) => process(sub_matches),
+        _ => process(&matches),
+    };
+
+    let sub_matches = matches
+        .subcommand()
+        .map_or(&matches, |(_, sub_matches)| sub_matches);
+    match result {
+        // Whoever was reading the output stopped, e.g. `| head`. There is nobody
+        // left to report an error to.
+        Err(err) if is_broken_pipe(&err) => Ok(()),
+        // Everything on stderr has to stay machine-readable, including the error
+        // that ends the run.
+        Err(err) if is_json_error_format(sub_matches) => {
+            eprintln!("{}", error_json(&err));
+            std::process::exit(1);
+        }
+        result => result,
     }
+}
 
-    Ok(transactions)
+/// The run was aborted because `--max-errors` transactions were rejected.
+#[derive(Error, Debug)]
+#[error("aborting after {rejected} rejected transactions. the last rejected transaction had id: \"{last_tx}\"")]
+struct Aborted {
+    rejected: u64,
+    last_tx: u32,
 }
 
-fn main() -> anyhow::Result<()> {
-    let matches = Command::new("MysteriousUnnamedRustProject")
-        .arg(Arg::new("transactions_csv_file").required(true))
-        .get_matches();
-    let transactions_csv_file_path = matches
-        .get_one::<String>("transactions_csv_file")
-        .expect("csv file path argument to exist");
-
-    let csv_file = File::open(transactions_csv_file_path).with_context(|| {
-        format!(
-            "csv file: \"{}\" does not exist",
-            transactions_csv_file_path
-        )
-    })?;
+/// `validate` found rejected transactions.
+#[derive(Error, Debug)]
+#[error("{0} transactions were rejected")]
+struct Rejected(u64);
+
+/// Arguments accepted both without a subcommand and by every subcommand, so
+/// that invoking the binary with just a csv file keeps working.
+fn shared_args() -> Vec<Arg> {
+    vec![
+        Arg::new("transactions_csv_file").required_unless_present("input_dir"),
+        Arg::new("input_dir")
+            .long("input-dir")
+            .value_name("DIR")
+            .conflicts_with_all(["transactions_csv_file", "order_by"])
+            .help("process every *.csv and *.csv.gz file in this directory, in file name order"),
+        Arg::new("input_format")
+            .long("input-format")
+            .value_parser(["csv", "json", "ndjson"])
+            .default_value("csv")
+            .conflicts_with_all(["input_dir", "order_by"])
+            .help("read the transactions as csv, a JSON array, or newline-delimited JSON"),
+        Arg::new("quote")
+            .long("quote")
+            .value_name("CHAR")
+            .value_parser(parse_csv_char)
+            .help("the character used to quote fields in the input, instead of '\"'"),
+        Arg::new("escape")
+            .long("escape")
+            .value_name("CHAR")
+            .value_parser(parse_csv_char)
+            .help("the character used to escape quotes in the input, instead of doubling them"),
+        Arg::new("no_headers")
+            .long("no-headers")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("order_by")
+            .help("read csv input without a header row, taking the columns by position"),
+        Arg::new("output")
+            .long("output")
+            .short('o')
+            .help("write the client csv to this file instead of stdout"),
+        Arg::new("holds_report")
+            .long("holds-report")
+            .value_name("FILE")
+            .help("write a csv of every currently disputed deposit to this file"),
+        Arg::new("metadata")
+            .long("metadata")
+            .value_name("FILE")
+            .help("write a csv with the processing timestamp and client count to this file"),
+        Arg::new("timezone")
+            .long("timezone")
+            .value_name("TZ")
+            .value_parser(|timezone: &str| timezone.parse::<Tz>())
+            .default_value("UTC")
+            .requires("metadata")
+            .help("the IANA timezone of the --metadata timestamp, e.g. Europe/Berlin"),
+        Arg::new("rounding")
+            .long("rounding")
+            .value_parser(["half-even", "half-up", "truncate"])
+            .default_value("half-even")
+            .help("how amounts are rounded to four decimal places in the output"),
+        Arg::new("locale")
+            .long("locale")
+            .value_parser(["en", "de"])
+            .default_value("en")
+            .help("use a period (en) or a comma (de) as the decimal separator in the output"),
+        Arg::new("minor_units")
+            .long("minor-units")
+            .action(ArgAction::SetTrue)
+            .help("write amounts as integers scaled by 10,000 instead of decimals"),
+        Arg::new("sort_by")
+            .long("sort-by")
+            .value_parser(["id", "total"])
+            .default_value("id")
+            .help("order clients by id, or by total descending with ties broken by id"),
+        Arg::new("progress")
+            .long("progress")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .help("print progress to stderr every N transactions"),
+        Arg::new("client_scoped_tx_ids")
+            .long("client-scoped-tx-ids")
+            .action(ArgAction::SetTrue)
+            .help("treat transaction ids as unique per client instead of globally"),
+        Arg::new("lock_freezes_all")
+            .long("lock-freezes-all")
+            .action(ArgAction::SetTrue)
+            .help("reject disputes and resolves on locked accounts, not just withdrawals"),
+        Arg::new("withdrawal_disputes")
+            .long("withdrawal-disputes")
+            .action(ArgAction::SetTrue)
+            .help("allow withdrawals to be disputed, resolved and charged back"),
+        Arg::new("strict")
+            .long("strict")
+            .action(ArgAction::SetTrue)
+            .help("reject deposits and withdrawals of zero instead of ignoring them"),
+        Arg::new("ignore_missing_disputes")
+            .long("ignore-missing-disputes")
+            .action(ArgAction::SetTrue)
+            .help("ignore resolves and chargebacks of deposits that have no open dispute"),
+        Arg::new("dedupe_across_files")
+            .long("dedupe-across-files")
+            .action(ArgAction::SetTrue)
+            .help("skip deposits and withdrawals that exactly repeat one already applied"),
+        Arg::new("pending_withdrawals")
+            .long("pending-withdrawals")
+            .action(ArgAction::SetTrue)
+            .help("hold withdrawals as pending until a settle or cancel transaction"),
+        Arg::new("dispute_policy")
+            .long("dispute-policy")
+            .value_name("POLICY")
+            .value_parser(["current-available", "original-funds"])
+            .default_value("current-available")
+            .help("whether a dispute needs the available funds or the deposit's own funds"),
+        Arg::new("overdraft_limit")
+            .long("overdraft-limit")
+            .value_name("X")
+            .value_parser(clap::value_parser!(f32))
+            .help("let withdrawals draw the available funds down to -X instead of zero"),
+        Arg::new("strict_balance")
+            .long("strict-balance")
+            .action(ArgAction::SetTrue)
+            .help("abort with an error if a transaction would leave a client's total below zero"),
+        Arg::new("seal_on_chargeback")
+            .long("seal-on-chargeback")
+            .action(ArgAction::SetTrue)
+            .help("reject every transaction for a charged back account until it is unlocked"),
+        Arg::new("prune_finalized")
+            .long("prune-finalized")
+            .action(ArgAction::SetTrue)
+            .help("drop charged back and voided deposits from memory"),
+        Arg::new("max_errors")
+            .long("max-errors")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .help("abort once N transactions have been rejected"),
+        Arg::new("error_format")
+            .long("error-format")
+            .value_name("FORMAT")
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .help("report rejected transactions to stderr as text, or as one JSON object per line"),
+        Arg::new("sample")
+            .long("sample")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .help("only process the first N transactions"),
+        Arg::new("round_trip_check")
+            .long("round-trip-check")
+            .action(ArgAction::SetTrue)
+            .help("re-parse the client csv and fail if it does not round-trip without loss"),
+        Arg::new("print_digest")
+            .long("print-digest")
+            .action(ArgAction::SetTrue)
+            .help("print a SHA-256 digest of the client table to stderr"),
+        Arg::new("histogram")
+            .long("histogram")
+            .value_name("BOUNDS")
+            .value_parser(parse_histogram_bounds)
+            .help("print the number of clients per balance bucket to stderr, e.g. 0,100,1000"),
+        Arg::new("min_total")
+            .long("min-total")
+            .value_name("X")
+            .value_parser(clap::value_parser!(f32))
+            .help("leave clients whose total is below X out of the output"),
+        Arg::new("columns")
+            .long("columns")
+            .value_name("COLUMNS")
+            .conflicts_with("round_trip_check")
+            .help(
+                "a comma separated, ordered subset of client,available,held,total,locked to write",
+            ),
+        Arg::new("annotate_errors")
+            .long("annotate-errors")
+            .action(ArgAction::SetTrue)
+            .help("add a had_errors column flagging clients with rejected transactions"),
+        Arg::new("last_reference")
+            .long("last-reference")
+            .action(ArgAction::SetTrue)
+            .help("add a last_reference column with each client's last transaction reference"),
+        Arg::new("order_by")
+            .long("order-by")
+            .value_name("COLUMN")
+            .help("sort transactions by the integer value of this column before processing"),
+        Arg::new("two_pass")
+            .long("two-pass")
+            .action(ArgAction::SetTrue)
+            .help(
+                "apply all deposits and withdrawals before any disputes, resolves or chargebacks",
+            ),
+        Arg::new("error_report")
+            .long("error-report")
+            .action(ArgAction::SetTrue)
+            .help("print a count of the rejected transactions by error to stderr at the end"),
+        Arg::new("explain")
+            .long("explain")
+            .value_name("TX_ID")
+            .value_parser(clap::value_parser!(u32))
+            .help("print a trace of every transaction with this id to stderr"),
+        Arg::new("split")
+            .long("split")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .requires("output")
+            .conflicts_with("stream_output")
+            .help("split the client csv into files of N clients named after --output"),
+        Arg::new("stream_output")
+            .long("stream-output")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("round_trip_check")
+            .help("write each client as soon as a finalize transaction is processed for it"),
+    ]
+}
+
+/// Parses a single ASCII character for the csv dialect flags.
+fn parse_csv_char(value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(format!("\"{}\" is not a single ASCII character", value)),
+    }
+}
 
-    let transactions = parse_csv(BufReader::new(csv_file))
-        .with_context(|| "failed to parse transactions from csv file")?;
+/// Processes every transaction in the input file with the configuration given on
+/// the command line. `on_rejected` is called for every transaction that is
+/// rejected without aborting the run, and `after_each` after every transaction.
+fn run(
+    matches: &ArgMatches,
+    mut on_rejected: impl FnMut(&Transaction, &ProcessTransactionError),
+    mut after_each: impl FnMut(&mut ClientRecords) -> anyhow::Result<()>,
+) -> anyhow::Result<ClientRecords> {
+    let mut progress = matches
+        .get_one::<u64>("progress")
+        .map(|interval| ProgressReporter::new(*interval));
+
+    let max_errors = matches.get_one::<u64>("max_errors").copied();
+    let explain = matches.get_one::<u32>("explain").copied();
+    let json_errors = is_json_error_format(matches);
+    let mut rejected_count: u64 = 0;
+    let mut error_report = matches.get_flag("error_report").then(ErrorReport::default);
 
     let mut client_records = ClientRecords::new();
-    for txn in transactions {
-        if let Err(err) = client_records.process_transaction(&txn) {
-            match err.downcast_ref::<ProcessTransactionError>() {
-                Some(_) => {
-                    // This is where you would do any complex error logic handling
-                    // i.e. log it to a server, send a push notification, etc...
+    client_records.set_client_scoped_tx_ids(matches.get_flag("client_scoped_tx_ids"));
+    client_records.set_lock_freezes_all(matches.get_flag("lock_freezes_all"));
+    client_records.set_withdrawal_disputes(matches.get_flag("withdrawal_disputes"));
+    client_records.set_strict(matches.get_flag("strict"));
+    client_records.set_ignore_missing_disputes(matches.get_flag("ignore_missing_disputes"));
+    client_records.set_dedupe_across_files(matches.get_flag("dedupe_across_files"));
+    client_records.set_pending_withdrawals(matches.get_flag("pending_withdrawals"));
+    if let Some(limit) = matches.get_one::<f32>("overdraft_limit") {
+        client_records.set_overdraft_limit(*limit);
+    }
+    client_records.set_strict_balance(matches.get_flag("strict_balance"));
+    client_records.set_dispute_policy(
+        matches
+            .get_one::<String>("dispute_policy")
+            .expect("dispute policy to have a default value")
+            .parse()?,
+    );
+    client_records.set_seal_on_chargeback(matches.get_flag("seal_on_chargeback"));
+    client_records.set_prune_finalized(matches.get_flag("prune_finalized"));
+    let sample = matches
+        .get_one::<usize>("sample")
+        .copied()
+        .unwrap_or(usize::MAX);
+    let mut dialect = CsvDialect::default();
+    if let Some(quote) = matches.get_one::<u8>("quote") {
+        dialect.quote = *quote;
+    }
+    dialect.escape = matches.get_one::<u8>("escape").copied();
+    dialect.has_headers = !matches.get_flag("no_headers");
+    let transactions: Box<dyn Iterator<Item = anyhow::Result<Transaction>>> =
+        if let Some(input_dir) = matches.get_one::<String>("input_dir") {
+            Box::new(
+                dialect
+                    .stream_csv_dir(Path::new(input_dir))
+                    .with_context(|| "failed to parse transactions from csv file")?,
+            )
+        } else {
+            let transactions_csv_file_path = matches
+                .get_one::<String>("transactions_csv_file")
+                .expect("csv file path argument to exist");
+
+            let csv_file = File::open(transactions_csv_file_path).with_context(|| {
+                format!(
+                    "csv file: \"{}\" does not exist",
+                    transactions_csv_file_path
+                )
+            })?;
+
+            let input_format: InputFormat = matches
+                .get_one::<String>("input_format")
+                .expect("input format to have a default value")
+                .parse()?;
+            match (input_format, matches.get_one::<String>("order_by")) {
+                (InputFormat::Json, _) => Box::new(
+                    parse_json(BufReader::new(csv_file))
+                        .with_context(|| "failed to parse transactions from json file")?
+                        .into_iter()
+                        .map(Ok),
+                ),
+                (InputFormat::Ndjson, _) => Box::new(stream_ndjson(BufReader::new(csv_file))?),
+                (InputFormat::Csv, Some(column)) => Box::new(
+                    dialect
+                        .parse_csv_ordered_by(BufReader::new(csv_file), column)
+                        .with_context(|| "failed to parse transactions from csv file")?
+                        .into_iter()
+                        .map(Ok),
+                ),
+                (InputFormat::Csv, None) => Box::new(
+                    dialect
+                        .stream_csv(BufReader::new(csv_file))
+                        .with_context(|| "failed to parse transactions from csv file")?,
+                ),
+            }
+        };
+    let transactions = transactions.take(sample);
+    let transactions: Box<dyn Iterator<Item = anyhow::Result<Transaction>>> =
+        if matches.get_flag("two_pass") {
+            // Every transaction has to be read before the first dispute can be applied.
+            let transactions = transactions
+                .collect::<anyhow::Result<Vec<Transaction>>>()
+                .with_context(|| "failed to parse transactions from csv file")?;
+            Box::new(two_pass_order(transactions).into_iter().map(Ok))
+        } else {
+            Box::new(transactions)
+        };
+    for result in transactions {
+        let txn = result.with_context(|| "failed to parse transactions from csv file")?;
+        let result = if explain == Some(txn.tx_id) {
+            let (result, explanation) = client_records.explain_transaction(&txn);
+            eprintln!("{}", explanation);
+            result
+        } else {
+            client_records.process_transaction(&txn)
+        };
+        match result {
+            Ok(()) => {}
+            Err(err) if err.is_recoverable() => {
+                // This is where you would do any complex error logic handling
+                // i.e. log it to a server, send a push notification, etc...
+                if json_errors {
+                    eprintln!("{}", rejection_json(&txn, &err));
+                }
+                on_rejected(&txn, &err);
+                if let Some(error_report) = error_report.as_mut() {
+                    error_report.record(&err);
+                }
+                rejected_count += 1;
+                if max_errors.is_some_and(|max_errors| rejected_count >= max_errors) {
+                    return Err(err).context(Aborted {
+                        rejected: rejected_count,
+                        last_tx: txn.tx_id,
+                    });
                 }
-                None => {
-                    return Err(err).with_context(|| {
-                        format!(
-                            "fatal error while processing transaction with id: \"{}\"",
-                            txn.tx_id
-                        )
-                    })
+            }
+            Err(err) => {
+                if json_errors {
+                    eprintln!("{}", rejection_json(&txn, &err));
                 }
+                return Err(err).with_context(|| {
+                    format!(
+                        "fatal error while processing transaction with id: \"{}\"",
+                        txn.tx_id
+                    )
+                });
             }
         }
+
+        after_each(&mut client_records)?;
+
+        if let Some(line) = progress.as_mut().and_then(ProgressReporter::tick) {
+            eprintln!("{}", line);
+        }
     }
 
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    for client_record in client_records.view().values() {
-        wtr.serialize(client_record)?;
+    if let Some(error_report) = error_report {
+        eprintln!("rejected transactions: {}", error_report.summary());
     }
 
-    wtr.flush()?;
+    Ok(client_records)
+}
+
+/// Whether `--error-format json` asked for rejected transactions to be reported
+/// as JSON rather than text.
+fn is_json_error_format(matches: &ArgMatches) -> bool {
+    matches
+        .get_one::<String>("error_format")
+        .is_some_and(|format| format == "json")
+}
+
+/// A rejected transaction as a single line JSON object, for `--error-format json`.
+fn rejection_json(txn: &Transaction, err: &ProcessTransactionError) -> serde_json::Value {
+    serde_json::json!({
+        "tx": txn.tx_id,
+        "client": txn.client_id,
+        "type": err.kind(),
+        "message": err.to_string(),
+    })
+}
+
+/// The error that ended the run as a single line JSON object, for
+/// `--error-format json`. An abort by `--max-errors` and the rejections found by
+/// `validate` also say how many transactions were rejected.
+fn error_json(err: &anyhow::Error) -> serde_json::Value {
+    let message = format!("{:#}", err);
+    if let Some(aborted) = err.downcast_ref::<Aborted>() {
+        serde_json::json!({
+            "aborted": true,
+            "rejected": aborted.rejected,
+            "last_tx": aborted.last_tx,
+            "message": message,
+        })
+    } else if let Some(Rejected(rejected)) = err.downcast_ref::<Rejected>() {
+        serde_json::json!({
+            "aborted": false,
+            "rejected": rejected,
+            "message": message,
+        })
+    } else {
+        serde_json::json!({ "message": message })
+    }
+}
+
+/// Parses the comma separated, ascending lower bounds of the `--histogram` buckets.
+fn parse_histogram_bounds(bounds: &str) -> anyhow::Result<Vec<f32>> {
+    let bounds = bounds
+        .split(',')
+        .map(|bound| {
+            bound
+                .trim()
+                .parse::<f32>()
+                .with_context(|| format!("invalid bucket bound: \"{}\"", bound))
+        })
+        .collect::<anyhow::Result<Vec<f32>>>()?;
+    if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(anyhow!("bucket bounds must be ascending"));
+    }
+
+    Ok(bounds)
+}
+
+fn output_format(matches: &ArgMatches) -> anyhow::Result<OutputFormat> {
+    Ok(OutputFormat {
+        rounding: matches
+            .get_one::<String>("rounding")
+            .expect("rounding to have a default value")
+            .parse()?,
+        min_total: matches.get_one::<f32>("min_total").copied(),
+        annotate_errors: matches.get_flag("annotate_errors"),
+        sort_by: matches
+            .get_one::<String>("sort_by")
+            .expect("sort order to have a default value")
+            .parse()?,
+        minor_units: matches.get_flag("minor_units"),
+        locale: matches
+            .get_one::<String>("locale")
+            .expect("locale to have a default value")
+            .parse()?,
+        columns: matches
+            .get_one::<String>("columns")
+            .map(|columns| Column::parse_list(columns))
+            .transpose()?,
+        pending: matches.get_flag("pending_withdrawals"),
+        last_reference: matches.get_flag("last_reference"),
+    })
+}
+
+/// Writes the output to the `--output` file, or to stdout.
+fn emit(matches: &ArgMatches, output: &[u8]) -> anyhow::Result<()> {
+    match matches.get_one::<String>("output") {
+        Some(output_file_path) => fs::write(output_file_path, output)
+            .with_context(|| format!("failed to write output file: \"{}\"", output_file_path))?,
+        None => io::stdout().write_all(output)?,
+    }
 
     Ok(())
 }
+
+fn process(matches: &ArgMatches) -> anyhow::Result<()> {
+    if matches.get_flag("stream_output") {
+        return process_streaming(matches);
+    }
+
+    let client_records = run(matches, |_, _| {}, |_| Ok(()))?;
+
+    // The output is buffered and only emitted once every transaction has been
+    // processed, so a fatal error never leaves a partial csv behind.
+    let output_format = output_format(matches)?;
+    let mut output = vec![];
+    write_csv(&client_records, &mut output, &output_format)?;
+
+    if matches.get_flag("round_trip_check") {
+        check_round_trip(&output, &output_format)?;
+    }
+
+    write_reports(matches, &client_records, &output_format)?;
+
+    match matches.get_one::<u64>("split") {
+        Some(&rows_per_file) => emit_split(
+            matches,
+            write_csv_split(&client_records, rows_per_file as usize, &output_format)?,
+        ),
+        None => emit(matches, &output),
+    }
+}
+
+/// Writes every chunk of a split client csv to its own file, named after the
+/// `--output` file with a zero padded index before the extension, e.g.
+/// `out_000.csv`, `out_001.csv` and so on.
+fn emit_split(matches: &ArgMatches, chunks: Vec<Vec<u8>>) -> anyhow::Result<()> {
+    let output_file_path = Path::new(
+        matches
+            .get_one::<String>("output")
+            .expect("--split to require --output"),
+    );
+    let stem = output_file_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let mut file_name = format!("{}_{:03}", stem, index);
+        if let Some(extension) = output_file_path.extension() {
+            file_name = format!("{}.{}", file_name, extension.to_string_lossy());
+        }
+        let chunk_path = output_file_path.with_file_name(file_name);
+        fs::write(&chunk_path, chunk).with_context(|| {
+            format!("failed to write output file: \"{}\"", chunk_path.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Like `process`, but writes each client as soon as it is finalized. Unlike the
+/// buffered output, a fatal error leaves the clients written so far behind.
+fn process_streaming(matches: &ArgMatches) -> anyhow::Result<()> {
+    let output_format = output_format(matches)?;
+    let output: Box<dyn Write> = match matches.get_one::<String>("output") {
+        Some(output_file_path) => {
+            Box::new(File::create(output_file_path).with_context(|| {
+                format!("failed to write output file: \"{}\"", output_file_path)
+            })?)
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut writer = StreamingCsvWriter::new(output, output_format.clone());
+    let client_records = run(
+        matches,
+        |_, _| {},
+        |client_records| writer.flush_finalized(client_records),
+    )?;
+    writer.finish(&client_records)?;
+
+    write_reports(matches, &client_records, &output_format)
+}
+
+/// Writes the reports that accompany the client table: the holds report, the
+/// run metadata, the balance histogram and the output digest.
+fn write_reports(
+    matches: &ArgMatches,
+    client_records: &ClientRecords,
+    output_format: &OutputFormat,
+) -> anyhow::Result<()> {
+    if let Some(holds_report_path) = matches.get_one::<String>("holds_report") {
+        let mut holds_report = vec![];
+        write_holds_csv(client_records, &mut holds_report, output_format)?;
+        fs::write(holds_report_path, holds_report)
+            .with_context(|| format!("failed to write holds report: \"{}\"", holds_report_path))?;
+    }
+
+    if let Some(metadata_path) = matches.get_one::<String>("metadata") {
+        let processed_at = Utc::now().with_timezone(
+            matches
+                .get_one::<Tz>("timezone")
+                .expect("timezone to have a default value"),
+        );
+        let mut metadata = vec![];
+        write_metadata_csv(client_records, &mut metadata, &processed_at)?;
+        fs::write(metadata_path, metadata)
+            .with_context(|| format!("failed to write metadata: \"{}\"", metadata_path))?;
+    }
+
+    if let Some(bounds) = matches.get_one::<Vec<f32>>("histogram") {
+        let histogram = client_records.balance_histogram(bounds);
+        for (index, (bound, count)) in histogram.iter().enumerate() {
+            match histogram.get(index + 1) {
+                Some((next, _)) => eprintln!("[{}, {}): {}", bound, next, count),
+                None => eprintln!("[{}, ..): {}", bound, count),
+            }
+        }
+    }
+
+    if matches.get_flag("print_digest") {
+        eprintln!("{}", client_records.output_digest());
+    }
+
+    Ok(())
+}
+
+fn validate(matches: &ArgMatches) -> anyhow::Result<()> {
+    let mut rejected_count: u64 = 0;
+    let json_errors = is_json_error_format(matches);
+    run(
+        matches,
+        |txn, err| {
+            rejected_count += 1;
+            if !json_errors {
+                eprintln!("rejected transaction with id: \"{}\": {}", txn.tx_id, err);
+            }
+        },
+        |_| Ok(()),
+    )?;
+
+    if rejected_count > 0 {
+        return Err(Rejected(rejected_count).into());
+    }
+
+    Ok(())
+}
+
+fn stats(matches: &ArgMatches) -> anyhow::Result<()> {
+    let client_records = run(matches, |_, _| {}, |_| Ok(()))?;
+
+    let mut output = vec![];
+    write_totals_csv(
+        &client_records.totals(),
+        &mut output,
+        &output_format(matches)?,
+    )?;
+
+    emit(matches, &output)
+}
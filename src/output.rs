@@ -0,0 +1,1304 @@
+use std::{
+    io::{self, Write},
+    iter,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, SecondsFormat};
+use chrono_tz::Tz;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Client, ClientId, ClientRecords, LedgerStore, LockReason, TransactionType};
+
+const DECIMAL_PLACES: usize = 4;
+const MINOR_UNITS_PER_UNIT: i128 = 10_000;
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum RoundingMode {
+    /// Ties round to the nearest even digit (banker's rounding).
+    #[default]
+    HalfEven,
+    /// Ties round away from zero.
+    HalfUp,
+    /// Extra digits are dropped.
+    Truncate,
+}
+
+impl FromStr for RoundingMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "truncate" => Ok(RoundingMode::Truncate),
+            _ => Err(anyhow!("unknown rounding mode: \"{}\"", s)),
+        }
+    }
+}
+
+impl RoundingMode {
+    /// Rounds an amount to four decimal places, returning it as a whole number of
+    /// ten-thousandths. Returns `None` if the amount is not finite or too large.
+    pub fn to_minor_units(&self, amount: f32) -> Option<i128> {
+        if !amount.is_finite() {
+            return None;
+        }
+
+        // Round the shortest decimal representation of the float rather than its
+        // binary approximation, so that `1.00005` is treated as an actual tie.
+        let repr = amount.abs().to_string();
+        let (int_digits, frac_digits) = repr.split_once('.').unwrap_or((&repr, ""));
+        let kept: String = frac_digits
+            .chars()
+            .chain(iter::repeat('0'))
+            .take(DECIMAL_PLACES)
+            .collect();
+        let dropped = frac_digits.get(DECIMAL_PLACES..).unwrap_or("");
+
+        let units = int_digits
+            .parse::<i128>()
+            .ok()?
+            .checked_mul(MINOR_UNITS_PER_UNIT)?
+            .checked_add(kept.parse::<i128>().ok()?)?;
+
+        let first_dropped = dropped.chars().next().unwrap_or('0');
+        let rest_is_zero = dropped.chars().skip(1).all(|c| c == '0');
+        let round_up = match self {
+            RoundingMode::HalfUp => first_dropped >= '5',
+            RoundingMode::HalfEven => {
+                first_dropped > '5' || (first_dropped == '5' && (!rest_is_zero || units % 2 == 1))
+            }
+            RoundingMode::Truncate => false,
+        };
+        let units = if round_up {
+            units.checked_add(1)?
+        } else {
+            units
+        };
+
+        Some(if amount.is_sign_negative() {
+            -units
+        } else {
+            units
+        })
+    }
+
+    pub fn format(&self, amount: f32) -> String {
+        match self.to_minor_units(amount) {
+            Some(units) => format_minor_units(units),
+            None => format!("{:.4}", amount),
+        }
+    }
+}
+
+/// The order clients are written in.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum SortOrder {
+    /// Ascending by client id.
+    #[default]
+    Id,
+    /// Descending by total, with ties broken by ascending client id.
+    Total,
+}
+
+impl FromStr for SortOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(SortOrder::Id),
+            "total" => Ok(SortOrder::Total),
+            _ => Err(anyhow!("unknown sort order: \"{}\"", s)),
+        }
+    }
+}
+
+/// Controls the decimal separator of formatted amounts.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Locale {
+    /// A period, e.g. `1.5000`.
+    #[default]
+    En,
+    /// A comma, e.g. `1,5000`. The csv writer quotes these amounts.
+    De,
+}
+
+impl FromStr for Locale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Locale::En),
+            "de" => Ok(Locale::De),
+            _ => Err(anyhow!("unknown locale: \"{}\"", s)),
+        }
+    }
+}
+
+/// A column of the client csv.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl Column {
+    /// Every column, in the default output order.
+    pub const ALL: [Column; 5] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+        }
+    }
+
+    /// Parses a comma separated, ordered list of column names such as
+    /// `client,total`. Every column may appear at most once.
+    pub fn parse_list(s: &str) -> anyhow::Result<Vec<Column>> {
+        let mut columns = vec![];
+        for name in s.split(',') {
+            let column: Column = name.trim().parse()?;
+            if columns.contains(&column) {
+                return Err(anyhow!("duplicate column: \"{}\"", column.name()));
+            }
+            columns.push(column);
+        }
+
+        Ok(columns)
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name() == s)
+            .ok_or_else(|| anyhow!("unknown column: \"{}\"", s))
+    }
+}
+
+fn format_minor_units(units: i128) -> String {
+    let sign = if units < 0 { "-" } else { "" };
+    let units = units.abs();
+    format!(
+        "{}{}.{:04}",
+        sign,
+        units / MINOR_UNITS_PER_UNIT,
+        units % MINOR_UNITS_PER_UNIT
+    )
+}
+
+/// Controls how client records are rendered in the output.
+#[derive(Debug, Default, Clone)]
+pub struct OutputFormat {
+    pub rounding: RoundingMode,
+    /// Clients whose total is below this amount are left out of the output.
+    pub min_total: Option<f32>,
+    /// Adds a `had_errors` column flagging clients with rejected transactions.
+    pub annotate_errors: bool,
+    pub sort_by: SortOrder,
+    /// Writes amounts as whole numbers of ten-thousandths instead of decimals.
+    pub minor_units: bool,
+    pub locale: Locale,
+    /// The columns to write, in order. All of them are written when unset.
+    pub columns: Option<Vec<Column>>,
+    /// Adds a `pending` column with the funds of withdrawals that are not settled yet.
+    pub pending: bool,
+    /// Adds a `last_reference` column with the reference of each client's last
+    /// transaction that carried one.
+    pub last_reference: bool,
+}
+
+impl OutputFormat {
+    /// Renders an amount as a four decimal place string using the locale's decimal
+    /// separator, or as an integer number of minor units when `minor_units` is set.
+    pub fn format_amount(&self, amount: f32) -> String {
+        match self.rounding.to_minor_units(amount) {
+            Some(units) => self.format_units(units),
+            None => self.localize(format!("{:.4}", amount)),
+        }
+    }
+
+    /// Renders a client's total as the sum of its rounded balances rather than as
+    /// the rounded sum, so that the written total always equals the written
+    /// available, held and pending funds added up.
+    pub fn format_total(&self, client: &Client) -> String {
+        let units = [
+            client.available_amounts,
+            client.held_amounts,
+            client.pending_amounts,
+        ]
+        .into_iter()
+        .map(|amount| self.rounding.to_minor_units(amount))
+        .try_fold(0i128, |sum, units| sum.checked_add(units?));
+        match units {
+            Some(units) => self.format_units(units),
+            None => self.format_amount(client.total_amounts()),
+        }
+    }
+
+    /// Whether `client` is written at all, i.e. not left out by `min_total`.
+    pub fn includes(&self, client: &Client) -> bool {
+        !self
+            .min_total
+            .is_some_and(|min_total| client.total_amounts() < min_total)
+    }
+
+    fn format_units(&self, units: i128) -> String {
+        if self.minor_units {
+            units.to_string()
+        } else {
+            self.localize(format_minor_units(units))
+        }
+    }
+
+    fn localize(&self, amount: String) -> String {
+        match self.locale {
+            Locale::En => amount,
+            Locale::De => amount.replace('.', ","),
+        }
+    }
+
+    /// Parses an amount written by `format_amount` back into a float.
+    pub fn parse_amount(&self, amount: &str) -> anyhow::Result<f32> {
+        if self.minor_units {
+            let units: i128 = amount.parse()?;
+            return Ok((units as f64 / MINOR_UNITS_PER_UNIT as f64) as f32);
+        }
+
+        let amount = match self.locale {
+            Locale::En => amount.to_string(),
+            Locale::De => amount.replace(',', "."),
+        };
+        Ok(amount.parse()?)
+    }
+}
+
+/// A row of the client csv with its amounts still formatted.
+#[derive(Deserialize)]
+struct ClientRow {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    #[serde(default)]
+    pending: Option<String>,
+    #[serde(default)]
+    last_reference: Option<String>,
+    #[serde(default)]
+    had_errors: bool,
+}
+
+impl ClientRow {
+    fn into_client(self, format: &OutputFormat) -> anyhow::Result<Client> {
+        let client = Client {
+            client_id: self.client,
+            available_amounts: format.parse_amount(&self.available)?,
+            held_amounts: format.parse_amount(&self.held)?,
+            pending_amounts: self
+                .pending
+                .map_or(Ok(0.0), |pending| format.parse_amount(&pending))?,
+            lock_reason: self.locked.then_some(LockReason::Unknown),
+            had_errors: self.had_errors,
+            last_reference: self.last_reference,
+        };
+        if format.format_total(&client) != self.total {
+            return Err(anyhow!(
+                "total of client: {} does not match its available and held funds",
+                self.client
+            ));
+        }
+
+        Ok(client)
+    }
+}
+
+/// Reads a client written with the default output format.
+impl<'de> Deserialize<'de> for Client {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        ClientRow::deserialize(deserializer)?
+            .into_client(&OutputFormat::default())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Re-parses csv written by `write_csv` with `format` and writes it again, failing
+/// if the result is not byte for byte identical, i.e. if the output lost anything.
+pub fn check_round_trip(output: &[u8], format: &OutputFormat) -> anyhow::Result<()> {
+    let mut rdr = csv::Reader::from_reader(output);
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for row in rdr.deserialize::<ClientRow>() {
+        let client = row?.into_client(format)?;
+        wtr.serialize(FormattedClient::new(&client, format))?;
+    }
+    let rewritten = wtr.into_inner().context("failed to rewrite client csv")?;
+
+    if rewritten != output {
+        return Err(anyhow!(
+            "round trip check failed. the re-parsed output differs from the original"
+        ));
+    }
+
+    Ok(())
+}
+
+/// A client paired with the format it should be serialized with.
+pub struct FormattedClient<'a> {
+    client: &'a Client,
+    format: &'a OutputFormat,
+}
+
+impl<'a> FormattedClient<'a> {
+    pub fn new(client: &'a Client, format: &'a OutputFormat) -> FormattedClient<'a> {
+        FormattedClient { client, format }
+    }
+}
+
+impl Serialize for FormattedClient<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let client = self.client;
+        let format = self.format;
+        let columns = format.columns.as_deref().unwrap_or(&Column::ALL);
+        let field_count = columns.len()
+            + usize::from(format.pending)
+            + usize::from(format.last_reference)
+            + usize::from(format.annotate_errors);
+        let mut state = serializer.serialize_struct("Client", field_count)?;
+        for column in columns {
+            match column {
+                Column::Client => state.serialize_field("client", &client.client_id)?,
+                Column::Available => state.serialize_field(
+                    "available",
+                    &format.format_amount(client.available_amounts),
+                )?,
+                Column::Held => {
+                    state.serialize_field("held", &format.format_amount(client.held_amounts))?
+                }
+                Column::Total => state.serialize_field("total", &format.format_total(client))?,
+                Column::Locked => state.serialize_field("locked", &client.is_locked())?,
+            }
+        }
+        if format.pending {
+            state.serialize_field("pending", &format.format_amount(client.pending_amounts))?;
+        }
+        if format.last_reference {
+            state.serialize_field("last_reference", &client.last_reference)?;
+        }
+        if self.format.annotate_errors {
+            state.serialize_field("had_errors", &client.had_errors)?;
+        }
+        state.end()
+    }
+}
+
+/// Writes the client records as csv, in the order given by `format.sort_by`.
+pub fn write_csv(
+    client_records: &ClientRecords<impl LedgerStore>,
+    writer: impl Write,
+    format: &OutputFormat,
+) -> anyhow::Result<()> {
+    write_to_sink(
+        client_records,
+        &mut CsvSink::new(writer, format.clone()),
+        format,
+    )
+}
+
+/// Writes the client table to `sink`, sorted and filtered the same way as
+/// `write_csv`, and finishes the sink once every client has been written.
+pub fn write_to_sink(
+    client_records: &ClientRecords<impl LedgerStore>,
+    sink: &mut impl RecordSink,
+    format: &OutputFormat,
+) -> anyhow::Result<()> {
+    for client_record in output_clients(client_records, format) {
+        sink.write_client(client_record)?;
+    }
+
+    sink.finish()
+}
+
+/// Writes the client table split into chunks of at most `rows_per_file` clients,
+/// each with its own header row, and returns the csv of every chunk in order.
+/// The clients are sorted and filtered as by `write_csv` before they are split.
+/// An empty table produces a single empty chunk, like `write_csv` does.
+pub fn write_csv_split(
+    client_records: &ClientRecords<impl LedgerStore>,
+    rows_per_file: usize,
+    format: &OutputFormat,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    if rows_per_file == 0 {
+        return Err(anyhow!("rows per file must be greater than zero"));
+    }
+
+    let clients = output_clients(client_records, format);
+    if clients.is_empty() {
+        return Ok(vec![vec![]]);
+    }
+
+    clients
+        .chunks(rows_per_file)
+        .map(|chunk| {
+            let mut output = vec![];
+            write_clients(chunk, &mut output, format)?;
+            Ok(output)
+        })
+        .collect()
+}
+
+/// The clients that make up the client table, in output order and without the
+/// ones left out by `min_total`.
+fn output_clients<'a>(
+    client_records: &'a ClientRecords<impl LedgerStore>,
+    format: &OutputFormat,
+) -> Vec<&'a Client> {
+    let mut clients = client_records.sorted_clients();
+    if format.sort_by == SortOrder::Total {
+        // The sort is stable, so clients with equal totals stay ordered by id.
+        clients.sort_by(|a, b| b.total_amounts().total_cmp(&a.total_amounts()));
+    }
+
+    clients.retain(|client_record| format.includes(client_record));
+    clients
+}
+
+fn write_clients(
+    clients: &[&Client],
+    writer: impl Write,
+    format: &OutputFormat,
+) -> anyhow::Result<()> {
+    let mut sink = CsvSink::new(writer, format.clone());
+    for client_record in clients {
+        sink.write_client(client_record)?;
+    }
+
+    sink.finish()
+}
+
+/// A destination for the client table, such as a file, a database or a socket.
+/// Clients are passed to it one at a time, in output order.
+pub trait RecordSink {
+    fn write_client(&mut self, client: &Client) -> anyhow::Result<()>;
+
+    /// Called once after the last client, e.g. to flush buffered output.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes clients as csv rows, with a header row before the first one.
+pub struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+    format: OutputFormat,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W, format: OutputFormat) -> CsvSink<W> {
+        CsvSink {
+            writer: csv::Writer::from_writer(writer),
+            format,
+        }
+    }
+}
+
+impl<W: Write> RecordSink for CsvSink<W> {
+    fn write_client(&mut self, client: &Client) -> anyhow::Result<()> {
+        self.writer
+            .serialize(FormattedClient::new(client, &self.format))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes clients as a JSON array of objects with the same fields and formatted
+/// amounts as the csv columns.
+pub struct JsonSink<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    wrote_clients: bool,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W, format: OutputFormat) -> JsonSink<W> {
+        JsonSink {
+            writer,
+            format,
+            wrote_clients: false,
+        }
+    }
+}
+
+impl<W: Write> RecordSink for JsonSink<W> {
+    fn write_client(&mut self, client: &Client) -> anyhow::Result<()> {
+        let separator = if self.wrote_clients { "," } else { "[" };
+        self.writer.write_all(separator.as_bytes())?;
+        serde_json::to_writer(
+            &mut self.writer,
+            &FormattedClient::new(client, &self.format),
+        )?;
+        self.wrote_clients = true;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let closing = if self.wrote_clients { "]" } else { "[]" };
+        self.writer.write_all(closing.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the client table incrementally. Clients are written as soon as a finalize
+/// transaction guarantees their balances can no longer change, and the rest are
+/// written in id order by `finish`. The output is therefore only sorted within
+/// each of those two parts, and `sort_by` is ignored.
+pub struct StreamingCsvWriter<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    wrote_headers: bool,
+}
+
+impl<W: Write> StreamingCsvWriter<W> {
+    pub fn new(writer: W, format: OutputFormat) -> StreamingCsvWriter<W> {
+        StreamingCsvWriter {
+            writer,
+            format,
+            wrote_headers: false,
+        }
+    }
+
+    /// Writes and flushes every client finalized since the last call.
+    pub fn flush_finalized(
+        &mut self,
+        client_records: &mut ClientRecords<impl LedgerStore>,
+    ) -> anyhow::Result<()> {
+        let clients = client_records.drain_finalized();
+        self.write_clients(clients.iter())?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes every client that was never finalized and returns the underlying writer.
+    pub fn finish(mut self, client_records: &ClientRecords<impl LedgerStore>) -> anyhow::Result<W> {
+        self.write_clients(
+            client_records
+                .sorted_clients()
+                .into_iter()
+                .filter(|client_record| !client_records.is_finalized(client_record.client_id)),
+        )?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    fn write_clients<'a>(
+        &mut self,
+        clients: impl Iterator<Item = &'a Client>,
+    ) -> anyhow::Result<()> {
+        // The header row is only written along with the first client, the same as
+        // `write_csv` does.
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(!self.wrote_headers)
+            .from_writer(&mut self.writer);
+        for client_record in clients.filter(|client_record| self.format.includes(client_record)) {
+            wtr.serialize(FormattedClient::new(client_record, &self.format))?;
+            self.wrote_headers = true;
+        }
+
+        wtr.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Whether writing the output failed because the reader went away, e.g. when the
+/// output is piped into `head`. That is not a failure of the run itself.
+pub fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// Writes a `client,tx,held_amount` row for every deposit that is currently
+/// disputed, ordered by client id and then transaction id.
+pub fn write_holds_csv(
+    client_records: &ClientRecords<impl LedgerStore>,
+    writer: impl Write,
+    format: &OutputFormat,
+) -> anyhow::Result<()> {
+    let mut holds: Vec<(ClientId, u32, f32)> = client_records
+        .ledger
+        .deposits()
+        .filter(|(_, deposit)| deposit.status == TransactionType::Dispute)
+        .map(|((_, tx_id), deposit)| (deposit.client_id, tx_id, deposit.disputed_amount))
+        .collect();
+    holds.sort_by_key(|(client_id, tx_id, _)| (*client_id, *tx_id));
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["client", "tx", "held_amount"])?;
+    for (client_id, tx_id, amount) in holds {
+        wtr.write_record([
+            client_id.to_string(),
+            tx_id.to_string(),
+            format.format_amount(amount),
+        ])?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes a single csv row describing the run: when the client table was produced,
+/// in the timezone of `processed_at`, and how many clients it holds.
+pub fn write_metadata_csv(
+    client_records: &ClientRecords<impl LedgerStore>,
+    writer: impl Write,
+    processed_at: &DateTime<Tz>,
+) -> anyhow::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["processed_at", "timezone", "clients"])?;
+    wtr.write_record([
+        processed_at.to_rfc3339_opts(SecondsFormat::Secs, false),
+        processed_at.timezone().name().to_string(),
+        client_records.view().len().to_string(),
+    ])?;
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Aggregate balances across every client.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Totals {
+    pub clients: usize,
+    pub locked_clients: usize,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+}
+
+/// Writes the aggregate totals as a single csv row.
+pub fn write_totals_csv(
+    totals: &Totals,
+    writer: impl Write,
+    format: &OutputFormat,
+) -> anyhow::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["clients", "locked", "available", "held", "total"])?;
+    wtr.write_record([
+        totals.clients.to_string(),
+        totals.locked_clients.to_string(),
+        format.format_amount(totals.available),
+        format.format_amount(totals.held),
+        format.format_amount(totals.total),
+    ])?;
+    wtr.flush()?;
+
+    Ok(())
+}
+
+impl<S: LedgerStore> ClientRecords<S> {
+    /// Sums the balances of every client, locked or not.
+    pub fn totals(&self) -> Totals {
+        self.records
+            .values()
+            .fold(Totals::default(), |mut totals, client| {
+                totals.clients += 1;
+                totals.locked_clients += usize::from(client.is_locked());
+                totals.available += client.available_amounts;
+                totals.held += client.held_amounts;
+                totals.total += client.total_amounts();
+                totals
+            })
+    }
+
+    /// Computes a hex encoded SHA-256 digest of the client table in its canonical
+    /// form: the default csv output ordered by client id. Identical balances always
+    /// produce the same digest regardless of the order they were reached in.
+    pub fn output_digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        write_csv(self, &mut hasher, &OutputFormat::default())
+            .expect("writing to a hasher to be infallible");
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_csv, Transaction};
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_round_a_tie_according_to_the_rounding_mode() {
+        assert_that!(RoundingMode::HalfUp.format(1.00005)).is_equal_to("1.0001".to_string());
+        assert_that!(RoundingMode::HalfEven.format(1.00005)).is_equal_to("1.0000".to_string());
+        assert_that!(RoundingMode::Truncate.format(1.00005)).is_equal_to("1.0000".to_string());
+
+        assert_that!(RoundingMode::HalfUp.format(1.00015)).is_equal_to("1.0002".to_string());
+        assert_that!(RoundingMode::HalfEven.format(1.00015)).is_equal_to("1.0002".to_string());
+        assert_that!(RoundingMode::Truncate.format(1.00015)).is_equal_to("1.0001".to_string());
+
+        assert_that!(RoundingMode::HalfUp.format(-2.00005)).is_equal_to("-2.0001".to_string());
+        assert_that!(RoundingMode::Truncate.format(-2.00005)).is_equal_to("-2.0000".to_string());
+    }
+
+    #[test]
+    fn it_should_round_non_ties_to_the_nearest_value() {
+        for mode in [RoundingMode::HalfUp, RoundingMode::HalfEven] {
+            assert_that!(mode.format(2.00006)).is_equal_to("2.0001".to_string());
+            assert_that!(mode.format(2.000049)).is_equal_to("2.0000".to_string());
+            assert_that!(mode.format(10.0)).is_equal_to("10.0000".to_string());
+        }
+        assert_that!(RoundingMode::Truncate.format(2.00009)).is_equal_to("2.0000".to_string());
+    }
+
+    #[test]
+    fn it_should_produce_a_stable_output_digest() {
+        let build = |withdrawal: f32| {
+            let mut client_records = ClientRecords::new();
+            for txn in [
+                Transaction::new_deposit_txn(2, 1, 5.0),
+                Transaction::new_deposit_txn(1, 2, 10.0),
+                Transaction::new_withdrawal_txn(1, 3, withdrawal),
+            ] {
+                client_records.process_transaction(&txn).unwrap();
+            }
+            client_records
+        };
+
+        let digest = build(1.0).output_digest();
+        assert_that!(digest.len()).is_equal_to(64);
+        assert_that!(build(1.0).output_digest()).is_equal_to(&digest);
+        assert_that!(build(1.5).output_digest()).is_not_equal_to(&digest);
+    }
+
+    #[test]
+    fn it_should_write_a_total_that_adds_up_to_the_written_balances() {
+        let client = Client::new_with_balances(1, 1.00005, 1.00005, false);
+        let format = OutputFormat {
+            rounding: RoundingMode::HalfUp,
+            ..Default::default()
+        };
+        // Rounding the sum instead of the parts would write a total of 2.0001.
+        assert_that!(format.format_amount(client.total_amounts()))
+            .is_equal_to("2.0001".to_string());
+
+        let mut client_records = ClientRecords::new();
+        client_records.records.insert(1, client);
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &format).unwrap();
+
+        assert_that!(String::from_utf8(output.clone()).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n1,1.0001,1.0001,2.0002,false\n".to_string(),
+        );
+        assert_that!(check_round_trip(&output, &format)).is_ok();
+    }
+
+    #[test]
+    fn it_should_filter_clients_below_the_minimum_total() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+            Transaction::new_deposit_txn(2, 2, 5.0),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &OutputFormat::default()).unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n\
+             1,0.0000,0.0000,0.0000,true\n\
+             2,5.0000,0.0000,5.0000,false\n"
+                .to_string(),
+        );
+
+        let format = OutputFormat {
+            min_total: Some(0.0001),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &format).unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n2,5.0000,0.0000,5.0000,false\n".to_string(),
+        );
+        assert_that!(client_records.view().get(&1)).is_some();
+    }
+
+    #[test]
+    fn it_should_split_the_client_table_into_chunks_with_headers() {
+        let mut client_records = ClientRecords::new();
+        for tx_id in 1..=5 {
+            client_records
+                .process_transaction(&Transaction::new_deposit_txn(tx_id as ClientId, tx_id, 1.0))
+                .unwrap();
+        }
+
+        let chunks = write_csv_split(&client_records, 2, &OutputFormat::default()).unwrap();
+        let chunks: Vec<String> = chunks
+            .into_iter()
+            .map(|chunk| String::from_utf8(chunk).unwrap())
+            .collect();
+        assert_that!(chunks).is_equal_to(vec![
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n\
+             2,1.0000,0.0000,1.0000,false\n"
+                .to_string(),
+            "client,available,held,total,locked\n\
+             3,1.0000,0.0000,1.0000,false\n\
+             4,1.0000,0.0000,1.0000,false\n"
+                .to_string(),
+            "client,available,held,total,locked\n5,1.0000,0.0000,1.0000,false\n".to_string(),
+        ]);
+        assert_that!(write_csv_split(
+            &client_records,
+            0,
+            &OutputFormat::default()
+        ))
+        .is_err();
+    }
+
+    #[test]
+    fn it_should_write_the_client_table_to_a_custom_sink() {
+        struct CollectingSink {
+            clients: Vec<Client>,
+            finished: bool,
+        }
+
+        impl RecordSink for CollectingSink {
+            fn write_client(&mut self, client: &Client) -> anyhow::Result<()> {
+                self.clients.push(client.clone());
+                Ok(())
+            }
+
+            fn finish(&mut self) -> anyhow::Result<()> {
+                self.finished = true;
+                Ok(())
+            }
+        }
+
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(2, 1, 5.0),
+            Transaction::new_deposit_txn(1, 2, 10.0),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let mut sink = CollectingSink {
+            clients: vec![],
+            finished: false,
+        };
+        write_to_sink(&client_records, &mut sink, &OutputFormat::default()).unwrap();
+        assert_that!(sink.clients).is_equal_to(vec![
+            Client::new_with_balances(1, 0.0, 10.0, false),
+            Client::new_with_balances(2, 5.0, 0.0, false),
+        ]);
+        assert_that!(sink.finished).is_true();
+
+        let mut output = vec![];
+        write_to_sink(
+            &client_records,
+            &mut JsonSink::new(&mut output, OutputFormat::default()),
+            &OutputFormat::default(),
+        )
+        .unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "[{\"client\":1,\"available\":\"0.0000\",\"held\":\"10.0000\",\"total\":\"10.0000\",\"locked\":false},\
+             {\"client\":2,\"available\":\"5.0000\",\"held\":\"0.0000\",\"total\":\"5.0000\",\"locked\":false}]"
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_annotate_clients_that_had_rejected_transactions() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(2, 2, 5.0),
+            Transaction::new_withdrawal_txn(1, 3, 50.0),
+        ] {
+            let _ = client_records.process_transaction(&txn);
+        }
+
+        let format = OutputFormat {
+            annotate_errors: true,
+            ..Default::default()
+        };
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &format).unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "client,available,held,total,locked,had_errors\n\
+             1,10.0000,0.0000,10.0000,false,true\n\
+             2,5.0000,0.0000,5.0000,false,false\n"
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_write_the_last_reference_of_each_client() {
+        let input = "\
+type,client,tx,amount,reference
+deposit,1,1,10.0,inv-1
+withdrawal,1,2,2.5,inv-2
+withdrawal,1,3,50.0,inv-3
+deposit,2,4,5.0,
+";
+        let client_records = ClientRecords::from_transactions(parse_csv(input.as_bytes()).unwrap())
+            .unwrap()
+            .0;
+
+        let format = OutputFormat {
+            last_reference: true,
+            ..Default::default()
+        };
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &format).unwrap();
+        // The rejected withdrawal's reference is not passed through.
+        assert_that!(String::from_utf8(output.clone()).unwrap()).is_equal_to(
+            "client,available,held,total,locked,last_reference\n\
+             1,7.5000,0.0000,7.5000,false,inv-2\n\
+             2,5.0000,0.0000,5.0000,false,\n"
+                .to_string(),
+        );
+        assert_that!(check_round_trip(&output, &format)).is_ok();
+    }
+
+    #[test]
+    fn it_should_sum_the_balances_of_every_client() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_deposit_txn(2, 2, 5.0),
+            Transaction::new_dispute_txn(2, 2),
+            Transaction::new_deposit_txn(3, 3, 2.0),
+            Transaction::new_dispute_txn(3, 3),
+            Transaction::new_chargeback_txn(3, 3),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let mut output = vec![];
+        write_totals_csv(
+            &client_records.totals(),
+            &mut output,
+            &OutputFormat::default(),
+        )
+        .unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "clients,locked,available,held,total\n3,1,10.0000,5.0000,15.0000\n".to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_report_every_disputed_deposit_as_a_hold() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(2, 1, 5.0),
+            Transaction::new_deposit_txn(1, 2, 10.0),
+            Transaction::new_deposit_txn(1, 3, 1.5),
+            Transaction::new_deposit_txn(1, 4, 2.0),
+            Transaction::new_dispute_txn(2, 1),
+            Transaction::new_dispute_txn(1, 3),
+            Transaction::new_dispute_txn(1, 2),
+            Transaction::new_dispute_txn(1, 4),
+            Transaction::new_resolve_txn(1, 4),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let mut output = vec![];
+        write_holds_csv(&client_records, &mut output, &OutputFormat::default()).unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "client,tx,held_amount\n\
+             1,2,10.0000\n\
+             1,3,1.5000\n\
+             2,1,5.0000\n"
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_sort_clients_by_the_sort_order() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(3, 1, 5.0),
+            Transaction::new_deposit_txn(1, 2, 2.0),
+            Transaction::new_deposit_txn(4, 3, 7.0),
+            Transaction::new_deposit_txn(2, 4, 5.0),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let client_ids = |sort_by: SortOrder| {
+            let format = OutputFormat {
+                sort_by,
+                ..Default::default()
+            };
+            let mut output = vec![];
+            write_csv(&client_records, &mut output, &format).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_that!(client_ids(SortOrder::Id)).is_equal_to(vec![
+            "1".to_string(),
+            "2".to_string(),
+            "3".to_string(),
+            "4".to_string(),
+        ]);
+        assert_that!(client_ids(SortOrder::Total)).is_equal_to(vec![
+            "4".to_string(),
+            "2".to_string(),
+            "3".to_string(),
+            "1".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn it_should_write_amounts_in_minor_units() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 12.3456),
+            Transaction::new_deposit_txn(1, 2, 0.5),
+            Transaction::new_dispute_txn(1, 2),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let mut decimal = vec![];
+        write_csv(&client_records, &mut decimal, &OutputFormat::default()).unwrap();
+        assert_that!(String::from_utf8(decimal).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n1,12.3456,0.5000,12.8456,false\n".to_string(),
+        );
+
+        let format = OutputFormat {
+            minor_units: true,
+            ..Default::default()
+        };
+        let mut minor_units = vec![];
+        write_csv(&client_records, &mut minor_units, &format).unwrap();
+        assert_that!(String::from_utf8(minor_units).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n1,123456,5000,128456,false\n".to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_use_the_decimal_separator_of_the_locale() {
+        let mut client_records = ClientRecords::new();
+        client_records
+            .process_transaction(&Transaction::new_deposit_txn(1, 1, 1234.5))
+            .unwrap();
+
+        let render = |locale: Locale| {
+            let format = OutputFormat {
+                locale,
+                ..Default::default()
+            };
+            let mut output = vec![];
+            write_csv(&client_records, &mut output, &format).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_that!(render(Locale::En)).is_equal_to(
+            "client,available,held,total,locked\n1,1234.5000,0.0000,1234.5000,false\n".to_string(),
+        );
+        assert_that!(render(Locale::De)).is_equal_to(
+            "client,available,held,total,locked\n1,\"1234,5000\",\"0,0000\",\"1234,5000\",false\n"
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_round_trip_the_client_csv() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 12.3456),
+            Transaction::new_deposit_txn(2, 2, 0.5),
+            Transaction::new_dispute_txn(2, 2),
+            Transaction::new_chargeback_txn(2, 2),
+            Transaction::new_withdrawal_txn(1, 3, 50.0),
+        ] {
+            let _ = client_records.process_transaction(&txn);
+        }
+
+        for format in [
+            OutputFormat::default(),
+            OutputFormat {
+                locale: Locale::De,
+                annotate_errors: true,
+                ..Default::default()
+            },
+            OutputFormat {
+                minor_units: true,
+                sort_by: SortOrder::Total,
+                ..Default::default()
+            },
+        ] {
+            let mut output = vec![];
+            write_csv(&client_records, &mut output, &format).unwrap();
+            assert_that(&check_round_trip(&output, &format)).is_ok();
+        }
+
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &OutputFormat::default()).unwrap();
+        let clients: Vec<Client> = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_that!(clients.len()).is_equal_to(2);
+        assert_that!(clients[0].available_amounts).is_equal_to(12.3456);
+    }
+
+    #[test]
+    fn it_should_fail_the_round_trip_check_for_an_inconsistent_total() {
+        let output = "client,available,held,total,locked\n1,1.0000,2.0000,4.0000,false\n";
+        assert_that!(check_round_trip(
+            output.as_bytes(),
+            &OutputFormat::default()
+        ))
+        .is_err();
+    }
+
+    #[test]
+    fn it_should_stream_clients_as_they_are_finalized() {
+        let mut client_records = ClientRecords::new();
+        let mut writer = StreamingCsvWriter::new(vec![], OutputFormat::default());
+        for txn in [
+            Transaction::new_deposit_txn(2, 1, 10.0),
+            Transaction::new_deposit_txn(1, 2, 5.0),
+            Transaction::new_finalize_txn(2, 3),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+            writer.flush_finalized(&mut client_records).unwrap();
+        }
+        assert_that!(String::from_utf8(writer.get_ref().clone()).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n2,10.0000,0.0000,10.0000,false\n".to_string(),
+        );
+
+        for txn in [
+            Transaction::new_deposit_txn(3, 4, 1.0),
+            Transaction::new_withdrawal_txn(1, 5, 2.0),
+            Transaction::new_finalize_txn(1, 6),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+            writer.flush_finalized(&mut client_records).unwrap();
+        }
+        assert_that!(String::from_utf8(writer.get_ref().clone()).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n\
+             2,10.0000,0.0000,10.0000,false\n\
+             1,3.0000,0.0000,3.0000,false\n"
+                .to_string(),
+        );
+
+        let output = writer.finish(&client_records).unwrap();
+        assert_that!(String::from_utf8(output).unwrap()).is_equal_to(
+            "client,available,held,total,locked\n\
+             2,10.0000,0.0000,10.0000,false\n\
+             1,3.0000,0.0000,3.0000,false\n\
+             3,1.0000,0.0000,1.0000,false\n"
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn it_should_only_write_the_selected_columns_in_order() {
+        let mut client_records = ClientRecords::new();
+        for txn in [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_deposit_txn(2, 2, 5.0),
+        ] {
+            client_records.process_transaction(&txn).unwrap();
+        }
+
+        let format = OutputFormat {
+            columns: Some(Column::parse_list("total, client").unwrap()),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        write_csv(&client_records, &mut output, &format).unwrap();
+        assert_that!(String::from_utf8(output).unwrap())
+            .is_equal_to("total,client\n10.0000,1\n5.0000,2\n".to_string());
+
+        assert_that!(Column::parse_list("client,client")).is_err();
+        assert_that!(Column::parse_list("client,balance")).is_err();
+    }
+
+    #[test]
+    fn it_should_detect_a_broken_pipe_while_writing_the_csv() {
+        struct ClosedPipe;
+
+        impl Write for ClosedPipe {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
+
+        let mut client_records = ClientRecords::new();
+        client_records
+            .process_transaction(&Transaction::new_deposit_txn(1, 1, 10.0))
+            .unwrap();
+
+        let err = write_csv(&client_records, ClosedPipe, &OutputFormat::default()).unwrap_err();
+        assert_that!(is_broken_pipe(&err)).is_true();
+        assert_that!(is_broken_pipe(&err.context("failed to write output"))).is_true();
+        assert_that!(is_broken_pipe(&anyhow!(io::Error::from(
+            io::ErrorKind::PermissionDenied
+        ))))
+        .is_false();
+    }
+}
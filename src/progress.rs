@@ -0,0 +1,65 @@
+use std::time::Instant;
+
+/// Counts processed transactions and produces a progress line every `interval`
+/// transactions, for reporting on long-running inputs.
+#[derive(Debug)]
+pub struct ProgressReporter {
+    interval: u64,
+    processed: u64,
+    started: Instant,
+}
+
+impl ProgressReporter {
+    pub fn new(interval: u64) -> ProgressReporter {
+        ProgressReporter {
+            interval: interval.max(1),
+            processed: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    /// Records one processed transaction, returning a progress line if this
+    /// transaction completes another interval.
+    pub fn tick(&mut self) -> Option<String> {
+        self.processed += 1;
+        if !self.processed.is_multiple_of(self.interval) {
+            return None;
+        }
+
+        Some(format!(
+            "processed {} transactions in {:.2?}",
+            self.processed,
+            self.started.elapsed()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_report_progress_every_interval() {
+        let mut progress = ProgressReporter::new(3);
+        let reported: Vec<u64> = (0..10)
+            .filter_map(|_| progress.tick().map(|_| progress.processed()))
+            .collect();
+
+        assert_that!(reported).is_equal_to(vec![3, 6, 9]);
+        assert_that!(progress.processed()).is_equal_to(10);
+    }
+
+    #[test]
+    fn it_should_include_the_count_in_the_progress_line() {
+        let mut progress = ProgressReporter::new(1);
+
+        assert_that!(progress.tick())
+            .is_some()
+            .matches(|line| line.starts_with("processed 1 transactions in "));
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use crate::ProcessTransactionError;
+
+/// Counts rejected transactions by the kind of error they were rejected with, for
+/// an end-of-run summary.
+#[derive(Debug, Default, Clone)]
+pub struct ErrorReport {
+    counts: HashMap<&'static str, u64>,
+}
+
+impl ErrorReport {
+    pub fn record(&mut self, err: &ProcessTransactionError) {
+        *self.counts.entry(err.kind()).or_default() += 1;
+    }
+
+    /// The count of every kind of error that was recorded, most frequent first,
+    /// with ties ordered by name so the report is the same on every run.
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<(&'static str, u64)> = self
+            .counts
+            .iter()
+            .map(|(kind, count)| (*kind, *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Summarizes the counts on a single line, e.g. `12 insufficient funds, 3
+    /// duplicate transaction`.
+    pub fn summary(&self) -> String {
+        if self.counts.is_empty() {
+            return "no rejected transactions".to_string();
+        }
+
+        self.counts()
+            .iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientRecords, Transaction};
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_count_the_errors_of_a_mixed_batch_by_kind() {
+        let mut client_records = ClientRecords::new();
+        let rejected = client_records
+            .process_transactions(&[
+                Transaction::new_deposit_txn(1, 1, 10.0),
+                Transaction::new_deposit_txn(1, 1, 10.0),
+                Transaction::new_withdrawal_txn(1, 2, 20.0),
+                Transaction::new_withdrawal_txn(1, 3, 30.0),
+                Transaction::new_dispute_txn(1, 9),
+                Transaction::new_dispute_txn(1, 1),
+                Transaction::new_chargeback_txn(1, 1),
+                Transaction::new_withdrawal_txn(1, 4, 1.0),
+                Transaction::new_deposit_txn(2, 5, 5.0),
+                Transaction::new_withdrawal_txn(2, 6, 6.0),
+            ])
+            .unwrap();
+
+        let mut report = ErrorReport::default();
+        for (_, err) in &rejected {
+            report.record(err);
+        }
+
+        assert_that!(report.counts()).is_equal_to(vec![
+            ("insufficient funds", 3),
+            ("duplicate transaction", 1),
+            ("frozen account", 1),
+            ("missing transaction", 1),
+        ]);
+        assert_that!(report.summary()).is_equal_to(
+            "3 insufficient funds, 1 duplicate transaction, 1 frozen account, 1 missing transaction"
+                .to_string(),
+        );
+        assert_that!(ErrorReport::default().summary())
+            .is_equal_to("no rejected transactions".to_string());
+    }
+}
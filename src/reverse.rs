@@ -0,0 +1,181 @@
+use crate::{ClientRecords, LedgerStore, ProcessTransactionError, Transaction, TransactionType};
+
+impl<S: LedgerStore> ClientRecords<S> {
+    /// Undoes a batch of transactions that were applied earlier, by applying their
+    /// inverses in reverse order: a deposit is taken back out and a withdrawal is
+    /// credited back, both leaving the ledger as if they never happened, while a
+    /// dispute and a resolve release or re-hold the disputed funds. Chargebacks,
+    /// voids, unlocks, finalizes, settles, cancels and reversals cannot be undone.
+    ///
+    /// Like `process_atomic`, either every transaction is undone or none is, and
+    /// the first error is returned.
+    pub fn reverse_apply(&mut self, txns: &[Transaction]) -> Result<(), ProcessTransactionError> {
+        let mut staged = self.clone();
+        for txn in txns.iter().rev() {
+            staged.reverse_transaction(txn)?;
+        }
+
+        *self = staged;
+
+        Ok(())
+    }
+
+    fn reverse_transaction(&mut self, txn: &Transaction) -> Result<(), ProcessTransactionError> {
+        let key = self.ledger_key(txn.client_id, txn.tx_id);
+        let missing =
+            ProcessTransactionError::MissingTransaction(txn.tx_id, txn.client_id, txn.txn_type);
+        let record = self
+            .records
+            .get_mut(&txn.client_id)
+            .ok_or(missing.clone())?;
+
+        match txn.txn_type {
+            TransactionType::Deposit => {
+                let deposit = self
+                    .ledger
+                    .deposit(key)
+                    .filter(|deposit| deposit.client_id == txn.client_id)
+                    .ok_or(missing)?;
+                // Undoing the dispute of a deposit that was ever resolved leaves it
+                // resolved rather than undisputed, which holds nothing either.
+                if !matches!(
+                    deposit.status,
+                    TransactionType::Deposit | TransactionType::Resolve
+                ) {
+                    return Err(ProcessTransactionError::InvalidTransactionState(
+                        txn.tx_id,
+                        txn.txn_type,
+                        deposit.status,
+                    ));
+                }
+                record
+                    .apply_withdrawal(deposit.amount, 0.0, self.epsilon)
+                    .map_err(|err| err.for_transaction(txn))?;
+                self.flows
+                    .entry(txn.client_id)
+                    .or_default()
+                    .removed
+                    .push((deposit.deposited_before, deposit.amount));
+                self.ledger.remove_deposit(key);
+                self.duplicates.remove(key);
+            }
+            TransactionType::Withdrawal => {
+                let withdrawal = self
+                    .ledger
+                    .withdrawal(key)
+                    .filter(|withdrawal| withdrawal.client_id == txn.client_id)
+                    .ok_or(missing)?;
+                if withdrawal.status != TransactionType::Withdrawal {
+                    return Err(ProcessTransactionError::InvalidTransactionState(
+                        txn.tx_id,
+                        txn.txn_type,
+                        withdrawal.status,
+                    ));
+                }
+
+                // A pending withdrawal still has its funds in the pending bucket.
+                if self.pending_withdrawals {
+                    record.pending_amounts = (record.pending_amounts - withdrawal.amount).max(0.0);
+                }
+                record.apply_deposit(withdrawal.amount);
+                self.flows.entry(txn.client_id).or_default().withdrawn -= withdrawal.amount;
+                self.ledger.remove_withdrawal(key);
+                self.duplicates.remove(key);
+            }
+            TransactionType::Dispute | TransactionType::Resolve => {
+                let deposit = self
+                    .ledger
+                    .deposit_mut(key)
+                    .filter(|deposit| deposit.client_id == txn.client_id)
+                    .ok_or(missing)?;
+                if deposit.status != txn.txn_type {
+                    return Err(ProcessTransactionError::InvalidTransactionState(
+                        txn.tx_id,
+                        txn.txn_type,
+                        deposit.status,
+                    ));
+                }
+
+                if txn.txn_type == TransactionType::Dispute {
+                    record
+                        .release(deposit.disputed_amount, self.epsilon)
+                        .map_err(|err| err.for_transaction(txn))?;
+                    // A deposit can only be disputed again after it was resolved.
+                    deposit.status = if deposit.was_resolved {
+                        TransactionType::Resolve
+                    } else {
+                        TransactionType::Deposit
+                    };
+                } else {
+                    record
+                        .hold(deposit.disputed_amount, self.epsilon)
+                        .map_err(|err| err.for_transaction(txn))?;
+                    deposit.status = TransactionType::Dispute;
+                }
+            }
+            TransactionType::Chargeback
+            | TransactionType::Void
+            | TransactionType::Unlock
+            | TransactionType::Finalize
+            | TransactionType::Settle
+            | TransactionType::Cancel
+            | TransactionType::Reversal => {
+                return Err(ProcessTransactionError::IrreversibleTransaction(
+                    txn.tx_id,
+                    txn.txn_type,
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_reverse_a_batch_back_to_zero() {
+        let txns = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 2.5),
+            Transaction::new_deposit_txn(2, 3, 4.0),
+            Transaction::new_dispute_txn(2, 3),
+            Transaction::new_resolve_txn(2, 3),
+        ];
+        let mut client_records = ClientRecords::new();
+        for txn in &txns {
+            assert_that(&client_records.process_transaction(txn)).is_ok();
+        }
+
+        assert_that(&client_records.reverse_apply(&txns)).is_ok();
+        for client in client_records.view().values() {
+            assert_that!(client.available_amounts).is_equal_to(0.0);
+            assert_that!(client.held_amounts).is_equal_to(0.0);
+        }
+        assert_that!(client_records.is_processed(1, 1)).is_false();
+        assert_that!(client_records.is_processed(1, 2)).is_false();
+        assert_that!(client_records.is_processed(2, 3)).is_false();
+    }
+
+    #[test]
+    fn it_should_refuse_to_reverse_a_chargeback() {
+        let txns = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_dispute_txn(1, 1),
+            Transaction::new_chargeback_txn(1, 1),
+        ];
+        let mut client_records = ClientRecords::new();
+        for txn in &txns {
+            assert_that(&client_records.process_transaction(txn)).is_ok();
+        }
+
+        assert_that(&client_records.reverse_apply(&txns)).is_err_containing(
+            ProcessTransactionError::IrreversibleTransaction(1, TransactionType::Chargeback),
+        );
+        assert_that!(client_records.view()[&1].is_locked()).is_true();
+        assert_that!(client_records.is_processed(1, 1)).is_true();
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::anyhow;
+
+use crate::ProcessTransactionError;
+
+/// A monotonically increasing counter handing out sequence numbers starting at 1.
+/// It fails once `u64::MAX` has been handed out instead of wrapping back around.
+#[derive(Debug, Default, Clone)]
+pub struct Sequence {
+    last: u64,
+}
+
+impl Sequence {
+    pub fn new() -> Sequence {
+        Sequence::default()
+    }
+
+    /// Creates a sequence whose next number is `last + 1`.
+    pub fn starting_after(last: u64) -> Sequence {
+        Sequence { last }
+    }
+
+    pub fn advance(&mut self) -> anyhow::Result<u64> {
+        self.last = self
+            .last
+            .checked_add(1)
+            .ok_or_else(|| anyhow!(ProcessTransactionError::SequenceExhausted))?;
+        Ok(self.last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_count_up_from_one() {
+        let mut sequence = Sequence::new();
+        assert_that!(sequence.advance().unwrap()).is_equal_to(1);
+        assert_that!(sequence.advance().unwrap()).is_equal_to(2);
+    }
+
+    #[test]
+    fn it_should_error_instead_of_wrapping_at_the_max_value() {
+        let mut sequence = Sequence::starting_after(u64::MAX - 1);
+        assert_that!(sequence.advance().unwrap()).is_equal_to(u64::MAX);
+        assert_that!(sequence.advance()).is_err().matches(|e| {
+            Some(&ProcessTransactionError::SequenceExhausted)
+                == e.downcast_ref::<ProcessTransactionError>()
+        });
+        assert_that!(sequence.advance()).is_err();
+    }
+}
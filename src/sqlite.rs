@@ -0,0 +1,82 @@
+use anyhow::Context;
+use rusqlite::{Connection, Row};
+use serde::{de::IntoDeserializer, Deserialize};
+
+use crate::{ClientRecords, ProcessTransactionError, Transaction, TransactionType};
+
+fn transaction_from_row(row: &Row) -> anyhow::Result<Transaction> {
+    let txn_type: String = row.get("type")?;
+    let txn_type = TransactionType::deserialize(txn_type.trim().into_deserializer())
+        .map_err(|err: serde::de::value::Error| anyhow::anyhow!(err))?;
+
+    Ok(Transaction::new(
+        txn_type,
+        row.get("client")?,
+        row.get("tx")?,
+        row.get::<_, Option<f64>>("amount")?
+            .map(|amount| amount as f32),
+    ))
+}
+
+impl ClientRecords {
+    /// Builds client records from the rows returned by `query`, processing them in
+    /// the order they are returned. The rows need the same `type`, `client`, `tx` and
+    /// `amount` columns as the csv input. Rejected transactions are collected the
+    /// same way as in `ClientRecords::from_transactions`.
+    pub fn from_sqlite(
+        conn: &Connection,
+        query: &str,
+    ) -> anyhow::Result<(ClientRecords, Vec<(u32, ProcessTransactionError)>)> {
+        let mut stmt = conn
+            .prepare(query)
+            .with_context(|| "failed to prepare sqlite query")?;
+        let txns = stmt
+            .query_and_then([], transaction_from_row)
+            .with_context(|| "failed to query transactions from sqlite")?
+            .collect::<anyhow::Result<Vec<Transaction>>>()
+            .with_context(|| "failed to read transaction from sqlite")?;
+
+        ClientRecords::from_transactions(txns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_csv;
+    use spectral::prelude::*;
+
+    #[test]
+    fn it_should_process_transactions_from_sqlite_like_the_csv_path() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE transactions (seq INTEGER, type TEXT, client INTEGER, tx INTEGER, amount REAL);
+             INSERT INTO transactions VALUES
+                (1, 'deposit', 1, 1, 10.0),
+                (2, 'withdrawal', 1, 2, 2.5),
+                (3, 'deposit', 2, 3, 5.0),
+                (4, 'dispute', 2, 3, NULL),
+                (5, 'withdrawal', 2, 4, 50.0);",
+        )
+        .unwrap();
+
+        let (client_records, rejected) = ClientRecords::from_sqlite(
+            &conn,
+            "SELECT type, client, tx, amount FROM transactions ORDER BY seq",
+        )
+        .unwrap();
+
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10.0\n\
+                   withdrawal,1,2,2.5\n\
+                   deposit,2,3,5.0\n\
+                   dispute,2,3,\n\
+                   withdrawal,2,4,50.0\n";
+        let (expected, expected_rejected) =
+            ClientRecords::from_transactions(parse_csv(csv.as_bytes()).unwrap()).unwrap();
+
+        assert_that!(client_records.view()).is_equal_to(expected.view());
+        assert_that!(rejected).is_equal_to(expected_rejected);
+        assert_that!(rejected.len()).is_equal_to(1);
+    }
+}
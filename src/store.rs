@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use crate::{Deposit, LedgerKey, Withdrawal};
+
+/// Where `ClientRecords` keeps its ledger of deposits and withdrawals, which grows
+/// with every transaction processed. The default `MemoryStore` keeps it in memory;
+/// other implementations could keep it in a database instead.
+///
+/// Client balances are deliberately left out and always stay in memory. There is
+/// only one per client rather than one per transaction, and `ClientRecords::view`
+/// hands them out as a map.
+pub trait LedgerStore: Clone {
+    fn deposit(&self, key: LedgerKey) -> Option<&Deposit>;
+
+    fn deposit_mut(&mut self, key: LedgerKey) -> Option<&mut Deposit>;
+
+    fn insert_deposit(&mut self, key: LedgerKey, deposit: Deposit);
+
+    fn remove_deposit(&mut self, key: LedgerKey) -> Option<Deposit>;
+
+    /// Every deposit in the ledger, in no particular order.
+    fn deposits(&self) -> Box<dyn Iterator<Item = (LedgerKey, &Deposit)> + '_>;
+
+    fn withdrawal(&self, key: LedgerKey) -> Option<&Withdrawal>;
+
+    fn withdrawal_mut(&mut self, key: LedgerKey) -> Option<&mut Withdrawal>;
+
+    fn insert_withdrawal(&mut self, key: LedgerKey, withdrawal: Withdrawal);
+
+    fn remove_withdrawal(&mut self, key: LedgerKey) -> Option<Withdrawal>;
+
+    /// Every withdrawal in the ledger, in no particular order.
+    fn withdrawals(&self) -> Box<dyn Iterator<Item = (LedgerKey, &Withdrawal)> + '_>;
+}
+
+/// Keeps the ledger in hash maps.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    deposits: HashMap<LedgerKey, Deposit>,
+    withdrawals: HashMap<LedgerKey, Withdrawal>,
+}
+
+impl MemoryStore {
+    /// Creates a store that can hold `transactions` deposits and as many
+    /// withdrawals without reallocating.
+    pub fn with_capacity(transactions: usize) -> MemoryStore {
+        MemoryStore {
+            deposits: HashMap::with_capacity(transactions),
+            withdrawals: HashMap::with_capacity(transactions),
+        }
+    }
+}
+
+impl LedgerStore for MemoryStore {
+    fn deposit(&self, key: LedgerKey) -> Option<&Deposit> {
+        self.deposits.get(&key)
+    }
+
+    fn deposit_mut(&mut self, key: LedgerKey) -> Option<&mut Deposit> {
+        self.deposits.get_mut(&key)
+    }
+
+    fn insert_deposit(&mut self, key: LedgerKey, deposit: Deposit) {
+        self.deposits.insert(key, deposit);
+    }
+
+    fn remove_deposit(&mut self, key: LedgerKey) -> Option<Deposit> {
+        self.deposits.remove(&key)
+    }
+
+    fn deposits(&self) -> Box<dyn Iterator<Item = (LedgerKey, &Deposit)> + '_> {
+        Box::new(self.deposits.iter().map(|(key, deposit)| (*key, deposit)))
+    }
+
+    fn withdrawal(&self, key: LedgerKey) -> Option<&Withdrawal> {
+        self.withdrawals.get(&key)
+    }
+
+    fn withdrawal_mut(&mut self, key: LedgerKey) -> Option<&mut Withdrawal> {
+        self.withdrawals.get_mut(&key)
+    }
+
+    fn insert_withdrawal(&mut self, key: LedgerKey, withdrawal: Withdrawal) {
+        self.withdrawals.insert(key, withdrawal);
+    }
+
+    fn remove_withdrawal(&mut self, key: LedgerKey) -> Option<Withdrawal> {
+        self.withdrawals.remove(&key)
+    }
+
+    fn withdrawals(&self) -> Box<dyn Iterator<Item = (LedgerKey, &Withdrawal)> + '_> {
+        Box::new(
+            self.withdrawals
+                .iter()
+                .map(|(key, withdrawal)| (*key, withdrawal)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{ClientRecords, Transaction};
+    use spectral::prelude::*;
+
+    /// Keeps the ledger in ordered maps and counts every write to them.
+    #[derive(Debug, Clone, Default)]
+    struct CountingStore {
+        deposits: BTreeMap<LedgerKey, Deposit>,
+        withdrawals: BTreeMap<LedgerKey, Withdrawal>,
+        writes: usize,
+    }
+
+    impl LedgerStore for CountingStore {
+        fn deposit(&self, key: LedgerKey) -> Option<&Deposit> {
+            self.deposits.get(&key)
+        }
+
+        fn deposit_mut(&mut self, key: LedgerKey) -> Option<&mut Deposit> {
+            self.deposits.get_mut(&key)
+        }
+
+        fn insert_deposit(&mut self, key: LedgerKey, deposit: Deposit) {
+            self.writes += 1;
+            self.deposits.insert(key, deposit);
+        }
+
+        fn remove_deposit(&mut self, key: LedgerKey) -> Option<Deposit> {
+            self.writes += 1;
+            self.deposits.remove(&key)
+        }
+
+        fn deposits(&self) -> Box<dyn Iterator<Item = (LedgerKey, &Deposit)> + '_> {
+            Box::new(self.deposits.iter().map(|(key, deposit)| (*key, deposit)))
+        }
+
+        fn withdrawal(&self, key: LedgerKey) -> Option<&Withdrawal> {
+            self.withdrawals.get(&key)
+        }
+
+        fn withdrawal_mut(&mut self, key: LedgerKey) -> Option<&mut Withdrawal> {
+            self.withdrawals.get_mut(&key)
+        }
+
+        fn insert_withdrawal(&mut self, key: LedgerKey, withdrawal: Withdrawal) {
+            self.writes += 1;
+            self.withdrawals.insert(key, withdrawal);
+        }
+
+        fn remove_withdrawal(&mut self, key: LedgerKey) -> Option<Withdrawal> {
+            self.writes += 1;
+            self.withdrawals.remove(&key)
+        }
+
+        fn withdrawals(&self) -> Box<dyn Iterator<Item = (LedgerKey, &Withdrawal)> + '_> {
+            Box::new(
+                self.withdrawals
+                    .iter()
+                    .map(|(key, withdrawal)| (*key, withdrawal)),
+            )
+        }
+    }
+
+    #[test]
+    fn it_should_process_transactions_with_a_custom_store() {
+        let txns = [
+            Transaction::new_deposit_txn(1, 1, 10.0),
+            Transaction::new_withdrawal_txn(1, 2, 2.5),
+            Transaction::new_deposit_txn(2, 3, 5.0),
+            Transaction::new_dispute_txn(2, 3),
+            Transaction::new_deposit_txn(1, 1, 10.0),
+        ];
+        let mut client_records = ClientRecords::with_store(CountingStore::default());
+        let mut expected = ClientRecords::new();
+        for txn in &txns {
+            let result = client_records.process_transaction(txn);
+            assert_that!(result.is_ok()).is_equal_to(expected.process_transaction(txn).is_ok());
+        }
+
+        assert_that!(client_records.view()).is_equal_to(expected.view());
+        assert_that!(client_records.is_processed(1, 2)).is_true();
+        assert_that!(client_records.ledger.writes).is_equal_to(3);
+    }
+}
@@ -1,70 +1,264 @@
 use std::fmt;
 
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[readonly::make]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub txn_type: TransactionType,
 
     #[serde(rename = "client")]
-    pub client_id: u16,
+    pub client_id: ClientId,
 
     #[serde(rename = "tx")]
     pub tx_id: u32,
 
+    #[serde(default, deserialize_with = "deserialize_finite_amount")]
     pub amount: Option<f32>,
+
+    /// An optional unique id for this event. Replaying an event that has already
+    /// been applied is a no-op instead of an error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+
+    /// An optional free-form reference, such as a memo or an invoice number, that
+    /// is passed through to the client's `last_reference`.
+    #[serde(default, alias = "memo", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+/// Rejects `inf` and `nan` amounts at parse time, since a single one would
+/// poison every balance it is added to.
+fn deserialize_finite_amount<'de, D>(deserializer: D) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f32>::deserialize(deserializer)? {
+        Some(amount) if !amount.is_finite() => Err(de::Error::custom(format!(
+            "amount: {} is not a finite number",
+            amount
+        ))),
+        amount => Ok(amount),
+    }
 }
 
 impl Transaction {
-    pub fn new_deposit_txn(client_id: u16, tx_id: u32, amount: f32) -> Transaction {
+    /// Creates a transaction of any type. Prefer the typed constructors below
+    /// when the type is known up front.
+    pub fn new(
+        txn_type: TransactionType,
+        client_id: ClientId,
+        tx_id: u32,
+        amount: Option<f32>,
+    ) -> Transaction {
+        Transaction {
+            txn_type,
+            client_id,
+            tx_id,
+            amount,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    /// Starts building a transaction field by field. Unlike the typed constructors,
+    /// the builder does not check that the fields make sense together, so it can
+    /// also build transactions the parser would accept but the ledger rejects.
+    pub fn builder() -> TransactionBuilder {
+        TransactionBuilder::default()
+    }
+
+    /// Attaches an event id, making replays of this exact transaction a no-op.
+    pub fn with_event_id(mut self, event_id: impl Into<String>) -> Transaction {
+        self.event_id = Some(event_id.into());
+        self
+    }
+
+    /// Attaches a reference that is passed through to the client.
+    pub fn with_reference(mut self, reference: impl Into<String>) -> Transaction {
+        self.reference = Some(reference.into());
+        self
+    }
+
+    pub fn new_deposit_txn(client_id: ClientId, tx_id: u32, amount: f32) -> Transaction {
         Transaction {
             txn_type: TransactionType::Deposit,
             client_id,
             tx_id,
             amount: Some(amount),
+            event_id: None,
+            reference: None,
         }
     }
 
-    pub fn new_withdrawal_txn(client_id: u16, tx_id: u32, amount: f32) -> Transaction {
+    pub fn new_withdrawal_txn(client_id: ClientId, tx_id: u32, amount: f32) -> Transaction {
         Transaction {
             txn_type: TransactionType::Withdrawal,
             client_id,
             tx_id,
             amount: Some(amount),
+            event_id: None,
+            reference: None,
         }
     }
 
-    pub fn new_dispute_txn(client_id: u16, tx_id: u32) -> Transaction {
+    pub fn new_dispute_txn(client_id: ClientId, tx_id: u32) -> Transaction {
         Transaction {
             txn_type: TransactionType::Dispute,
             client_id,
             tx_id,
             amount: None,
+            event_id: None,
+            reference: None,
         }
     }
 
-    pub fn new_resolve_txn(client_id: u16, tx_id: u32) -> Transaction {
+    pub fn new_resolve_txn(client_id: ClientId, tx_id: u32) -> Transaction {
         Transaction {
             txn_type: TransactionType::Resolve,
             client_id,
             tx_id,
             amount: None,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    pub fn new_void_txn(client_id: ClientId, tx_id: u32) -> Transaction {
+        Transaction {
+            txn_type: TransactionType::Void,
+            client_id,
+            tx_id,
+            amount: None,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    pub fn new_unlock_txn(client_id: ClientId, tx_id: u32) -> Transaction {
+        Transaction {
+            txn_type: TransactionType::Unlock,
+            client_id,
+            tx_id,
+            amount: None,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    pub fn new_finalize_txn(client_id: ClientId, tx_id: u32) -> Transaction {
+        Transaction {
+            txn_type: TransactionType::Finalize,
+            client_id,
+            tx_id,
+            amount: None,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    pub fn new_settle_txn(client_id: ClientId, tx_id: u32) -> Transaction {
+        Transaction {
+            txn_type: TransactionType::Settle,
+            client_id,
+            tx_id,
+            amount: None,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    pub fn new_cancel_txn(client_id: ClientId, tx_id: u32) -> Transaction {
+        Transaction {
+            txn_type: TransactionType::Cancel,
+            client_id,
+            tx_id,
+            amount: None,
+            event_id: None,
+            reference: None,
+        }
+    }
+
+    pub fn new_reversal_txn(client_id: ClientId, tx_id: u32) -> Transaction {
+        Transaction {
+            txn_type: TransactionType::Reversal,
+            client_id,
+            tx_id,
+            amount: None,
+            event_id: None,
+            reference: None,
         }
     }
 
-    pub fn new_chargeback_txn(client_id: u16, tx_id: u32) -> Transaction {
+    pub fn new_chargeback_txn(client_id: ClientId, tx_id: u32) -> Transaction {
         Transaction {
             txn_type: TransactionType::Chargeback,
             client_id,
             tx_id,
             amount: None,
+            event_id: None,
+            reference: None,
         }
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+/// Builds a `Transaction`. Every field not set defaults to a deposit for client 0
+/// with tx id 0 and no amount.
+#[derive(Debug, Default)]
+pub struct TransactionBuilder {
+    txn_type: Option<TransactionType>,
+    client_id: ClientId,
+    tx_id: u32,
+    amount: Option<f32>,
+    event_id: Option<String>,
+    reference: Option<String>,
+}
+
+impl TransactionBuilder {
+    pub fn txn_type(mut self, txn_type: TransactionType) -> TransactionBuilder {
+        self.txn_type = Some(txn_type);
+        self
+    }
+
+    pub fn client(mut self, client_id: ClientId) -> TransactionBuilder {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn tx(mut self, tx_id: u32) -> TransactionBuilder {
+        self.tx_id = tx_id;
+        self
+    }
+
+    pub fn amount(mut self, amount: f32) -> TransactionBuilder {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn event_id(mut self, event_id: impl Into<String>) -> TransactionBuilder {
+        self.event_id = Some(event_id.into());
+        self
+    }
+
+    pub fn reference(mut self, reference: impl Into<String>) -> TransactionBuilder {
+        self.reference = Some(reference.into());
+        self
+    }
+
+    pub fn build(self) -> Transaction {
+        Transaction {
+            txn_type: self.txn_type.unwrap_or(TransactionType::Deposit),
+            client_id: self.client_id,
+            tx_id: self.tx_id,
+            amount: self.amount,
+            event_id: self.event_id,
+            reference: self.reference,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 pub enum TransactionType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -76,6 +270,18 @@ pub enum TransactionType {
     Resolve,
     #[serde(rename = "chargeback")]
     Chargeback,
+    #[serde(rename = "void")]
+    Void,
+    #[serde(rename = "unlock")]
+    Unlock,
+    #[serde(rename = "finalize")]
+    Finalize,
+    #[serde(rename = "settle")]
+    Settle,
+    #[serde(rename = "cancel")]
+    Cancel,
+    #[serde(rename = "reversal")]
+    Reversal,
 }
 
 impl fmt::Display for TransactionType {
@@ -86,26 +292,132 @@ impl fmt::Display for TransactionType {
             TransactionType::Dispute => write!(f, "dispute"),
             TransactionType::Resolve => write!(f, "resolve"),
             TransactionType::Chargeback => write!(f, "chargeback"),
+            TransactionType::Void => write!(f, "void"),
+            TransactionType::Unlock => write!(f, "unlock"),
+            TransactionType::Finalize => write!(f, "finalize"),
+            TransactionType::Settle => write!(f, "settle"),
+            TransactionType::Cancel => write!(f, "cancel"),
+            TransactionType::Reversal => write!(f, "reversal"),
         }
     }
 }
 
 impl TransactionType {
-    pub(super) fn get_preceding_txn_state(&self) -> Option<TransactionType> {
+    /// Every transaction type, in declaration order.
+    pub const ALL: [TransactionType; 11] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Void,
+        TransactionType::Unlock,
+        TransactionType::Finalize,
+        TransactionType::Settle,
+        TransactionType::Cancel,
+        TransactionType::Reversal,
+    ];
+
+    /// The states a referenced deposit (or, for `settle` and `cancel`, withdrawal)
+    /// may be in for this transaction to apply. An empty slice means the transaction
+    /// does not reference an earlier one.
+    pub(super) const fn get_preceding_txn_states(&self) -> &'static [TransactionType] {
         match self {
-            TransactionType::Deposit => None,
-            TransactionType::Withdrawal => None,
-            TransactionType::Dispute => Some(TransactionType::Deposit),
-            TransactionType::Resolve | TransactionType::Chargeback => {
-                Some(TransactionType::Dispute)
-            }
+            TransactionType::Deposit => &[],
+            TransactionType::Withdrawal => &[],
+            TransactionType::Unlock => &[],
+            TransactionType::Finalize => &[],
+            // A resolved deposit can be disputed again.
+            TransactionType::Dispute => &[TransactionType::Deposit, TransactionType::Resolve],
+            TransactionType::Void => &[TransactionType::Deposit],
+            // Unlike a void, a reversal also applies to a deposit that was resolved.
+            TransactionType::Reversal => &[TransactionType::Deposit, TransactionType::Resolve],
+            TransactionType::Resolve | TransactionType::Chargeback => &[TransactionType::Dispute],
+            // Settling and cancelling reference a pending withdrawal instead.
+            TransactionType::Settle | TransactionType::Cancel => &[TransactionType::Withdrawal],
         }
     }
+
+    /// Whether a deposit in this state can never transition again, i.e. no
+    /// transaction type accepts it as a preceding state.
+    pub(super) fn is_final_state(&self) -> bool {
+        !transition_table()
+            .iter()
+            .any(|(_, preceding_txn_states)| preceding_txn_states.contains(self))
+    }
 }
 
-#[derive(Debug)]
-pub(super) struct Deposit {
-    pub(super) client_id: u16,
+/// Reorders transactions for two-pass processing: every deposit and withdrawal
+/// first, followed by the transactions that reference them (disputes, resolves,
+/// etc). Each group keeps its original relative order, so a dispute that appears
+/// in the input before its deposit can still find it. Unlocks and finalizes stay in
+/// the second group so that they still come after the transactions they follow.
+pub fn two_pass_order(txns: impl IntoIterator<Item = Transaction>) -> Vec<Transaction> {
+    let (mut ordered, referencing): (Vec<Transaction>, Vec<Transaction>) =
+        txns.into_iter().partition(|txn| {
+            matches!(
+                txn.txn_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        });
+    ordered.extend(referencing);
+    ordered
+}
+
+const TRANSITION_TABLE: [(TransactionType, &[TransactionType]); 11] = {
+    let mut table: [(TransactionType, &[TransactionType]); 11] =
+        [(TransactionType::Deposit, &[]); 11];
+    let mut i = 0;
+    while i < table.len() {
+        table[i] = transition(TransactionType::ALL[i]);
+        i += 1;
+    }
+    table
+};
+
+const fn transition(txn_type: TransactionType) -> (TransactionType, &'static [TransactionType]) {
+    (txn_type, txn_type.get_preceding_txn_states())
+}
+
+/// Lists every transaction type alongside the states its referenced deposit may be
+/// in for the transaction to apply. An empty slice means the transaction does not
+/// reference an earlier one.
+pub fn transition_table() -> &'static [(TransactionType, &'static [TransactionType])] {
+    &TRANSITION_TABLE
+}
+
+/// The type of client ids. A `u16` unless the `u32-client-ids` feature is enabled,
+/// for systems whose client ids do not fit in 16 bits.
+#[cfg(not(feature = "u32-client-ids"))]
+pub type ClientId = u16;
+#[cfg(feature = "u32-client-ids")]
+pub type ClientId = u32;
+
+/// Identifies a transaction in the ledger. The client id is only part of the
+/// key when transaction ids are scoped per client.
+pub type LedgerKey = (Option<ClientId>, u32);
+
+/// A deposit in the ledger and the state of its dispute.
+#[derive(Debug, Clone)]
+pub struct Deposit {
+    pub(super) client_id: ClientId,
+    pub(super) amount: f32,
+    pub(super) status: TransactionType,
+    /// Set once the deposit has been resolved, even if it was disputed again since.
+    pub(super) was_resolved: bool,
+    /// How much of the deposit the latest dispute holds. A dispute without an amount
+    /// holds the whole deposit.
+    pub(super) disputed_amount: f32,
+    /// How much the client had deposited before this deposit. Withdrawals draw
+    /// from the oldest deposits first, so this deposit is only drawn from once the
+    /// client has withdrawn more than this.
+    pub(super) deposited_before: f32,
+}
+
+/// A withdrawal in the ledger and the state of its dispute.
+#[derive(Debug, Clone)]
+pub struct Withdrawal {
+    pub(super) client_id: ClientId,
     pub(super) amount: f32,
     pub(super) status: TransactionType,
 }
@@ -116,14 +428,105 @@ mod tests {
     use spectral::prelude::*;
 
     #[test]
-    fn test_preceding_txn_state() {
-        assert_that!(TransactionType::Deposit.get_preceding_txn_state()).is_equal_to(None);
-        assert_that!(TransactionType::Withdrawal.get_preceding_txn_state()).is_equal_to(None);
-        assert_that!(TransactionType::Dispute.get_preceding_txn_state())
-            .is_equal_to(Some(TransactionType::Deposit));
-        assert_that!(TransactionType::Resolve.get_preceding_txn_state())
-            .is_equal_to(Some(TransactionType::Dispute));
-        assert_that!(TransactionType::Chargeback.get_preceding_txn_state())
-            .is_equal_to(Some(TransactionType::Dispute));
+    fn test_preceding_txn_states() {
+        assert_that!(TransactionType::Deposit.get_preceding_txn_states().to_vec()).is_empty();
+        assert_that!(TransactionType::Withdrawal
+            .get_preceding_txn_states()
+            .to_vec())
+        .is_empty();
+        assert_that!(TransactionType::Dispute.get_preceding_txn_states().to_vec())
+            .is_equal_to(vec![TransactionType::Deposit, TransactionType::Resolve]);
+        assert_that!(TransactionType::Resolve.get_preceding_txn_states().to_vec())
+            .is_equal_to(vec![TransactionType::Dispute]);
+        assert_that!(TransactionType::Chargeback
+            .get_preceding_txn_states()
+            .to_vec())
+        .is_equal_to(vec![TransactionType::Dispute]);
+        assert_that!(TransactionType::Void.get_preceding_txn_states().to_vec())
+            .is_equal_to(vec![TransactionType::Deposit]);
+        assert_that!(TransactionType::Unlock.get_preceding_txn_states().to_vec()).is_empty();
+        assert_that!(TransactionType::Finalize
+            .get_preceding_txn_states()
+            .to_vec())
+        .is_empty();
+        assert_that!(TransactionType::Settle.get_preceding_txn_states().to_vec())
+            .is_equal_to(vec![TransactionType::Withdrawal]);
+        assert_that!(TransactionType::Cancel.get_preceding_txn_states().to_vec())
+            .is_equal_to(vec![TransactionType::Withdrawal]);
+        assert_that!(TransactionType::Reversal
+            .get_preceding_txn_states()
+            .to_vec())
+        .is_equal_to(vec![TransactionType::Deposit, TransactionType::Resolve]);
+    }
+
+    #[test]
+    fn test_all_txn_types() {
+        assert_that!(TransactionType::ALL.len()).is_equal_to(11);
+        for (i, txn_type) in TransactionType::ALL.iter().enumerate() {
+            assert_that!(TransactionType::ALL[i + 1..].contains(txn_type)).is_false();
+            for preceding_txn_state in txn_type.get_preceding_txn_states() {
+                assert_that!(TransactionType::ALL.contains(preceding_txn_state)).is_true();
+            }
+        }
+    }
+
+    #[test]
+    fn test_transition_table() {
+        let table = transition_table();
+
+        for ((txn_type, preceding_txn_states), expected) in table.iter().zip(TransactionType::ALL) {
+            assert_that!(*txn_type).is_equal_to(expected);
+            assert_that!(txn_type.get_preceding_txn_states()).is_equal_to(*preceding_txn_states);
+        }
+        assert_that!(table.to_vec()).is_equal_to(vec![
+            (TransactionType::Deposit, &[][..]),
+            (TransactionType::Withdrawal, &[]),
+            (
+                TransactionType::Dispute,
+                &[TransactionType::Deposit, TransactionType::Resolve],
+            ),
+            (TransactionType::Resolve, &[TransactionType::Dispute]),
+            (TransactionType::Chargeback, &[TransactionType::Dispute]),
+            (TransactionType::Void, &[TransactionType::Deposit]),
+            (TransactionType::Unlock, &[]),
+            (TransactionType::Finalize, &[]),
+            (TransactionType::Settle, &[TransactionType::Withdrawal]),
+            (TransactionType::Cancel, &[TransactionType::Withdrawal]),
+            (
+                TransactionType::Reversal,
+                &[TransactionType::Deposit, TransactionType::Resolve],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_builder() {
+        let txn = Transaction::builder()
+            .txn_type(TransactionType::Dispute)
+            .client(2)
+            .tx(7)
+            .amount(1.5)
+            .event_id("evt")
+            .build();
+
+        assert_that!(txn.txn_type).is_equal_to(TransactionType::Dispute);
+        assert_that!(txn.client_id).is_equal_to(2);
+        assert_that!(txn.tx_id).is_equal_to(7);
+        assert_that!(txn.amount).is_equal_to(Some(1.5));
+        assert_that!(txn.event_id).is_equal_to(Some("evt".to_string()));
+
+        let txn = Transaction::builder().build();
+        assert_that!(txn.txn_type).is_equal_to(TransactionType::Deposit);
+        assert_that!(txn.amount).is_none();
+    }
+
+    #[test]
+    fn test_final_states() {
+        assert_that!(TransactionType::Deposit.is_final_state()).is_false();
+        assert_that!(TransactionType::Dispute.is_final_state()).is_false();
+        assert_that!(TransactionType::Resolve.is_final_state()).is_false();
+        assert_that!(TransactionType::Chargeback.is_final_state()).is_true();
+        assert_that!(TransactionType::Void.is_final_state()).is_true();
+        assert_that!(TransactionType::Reversal.is_final_state()).is_true();
     }
 }
@@ -0,0 +1,377 @@
+use std::process::{Command, Output};
+
+use spectral::prelude::*;
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mysterious_unnamed_rust_project"))
+        .args(args)
+        .output()
+        .expect("binary to run")
+}
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+fn it_should_write_the_output_file_on_success() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_path = dir.path().join("out.csv");
+
+    let output = run(&[&fixture("basic.csv"), "-o", output_path.to_str().unwrap()]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(output.stdout).is_empty();
+    let contents = std::fs::read_to_string(&output_path).unwrap();
+    assert_that!(contents.lines().count()).is_equal_to(3);
+    assert_that!(contents).contains("1,7.5000,0.0000,7.5000,false");
+    assert_that!(contents).contains("2,0.0000,5.0000,5.0000,false");
+}
+
+#[test]
+fn it_should_split_the_output_into_numbered_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_path = dir.path().join("out.csv");
+
+    let output = run(&[
+        &fixture("basic.csv"),
+        "-o",
+        output_path.to_str().unwrap(),
+        "--split",
+        "1",
+    ]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(output_path.exists()).is_false();
+    assert_that!(std::fs::read_to_string(dir.path().join("out_000.csv")).unwrap()).is_equal_to(
+        "client,available,held,total,locked\n1,7.5000,0.0000,7.5000,false\n".to_string(),
+    );
+    assert_that!(std::fs::read_to_string(dir.path().join("out_001.csv")).unwrap()).is_equal_to(
+        "client,available,held,total,locked\n2,0.0000,5.0000,5.0000,false\n".to_string(),
+    );
+    assert_that!(dir.path().join("out_002.csv").exists()).is_false();
+}
+
+#[test]
+fn it_should_write_the_processing_timestamp_in_the_requested_timezone() {
+    let dir = tempfile::tempdir().unwrap();
+    let metadata_path = dir.path().join("meta.csv");
+
+    let output = run(&[
+        &fixture("basic.csv"),
+        "--metadata",
+        metadata_path.to_str().unwrap(),
+        "--timezone",
+        "Asia/Kolkata",
+    ]);
+
+    assert_that!(output.status.success()).is_true();
+    let metadata = std::fs::read_to_string(&metadata_path).unwrap();
+    let mut lines = metadata.lines();
+    assert_that!(lines.next()).is_equal_to(Some("processed_at,timezone,clients"));
+    let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+    // e.g. 2024-01-31T17:30:00+05:30
+    assert_that!(row[0].len()).is_equal_to(25);
+    assert_that!(row[0]).ends_with("+05:30");
+    assert_that!(row[1..].to_vec()).is_equal_to(vec!["Asia/Kolkata", "2"]);
+
+    let output = run(&[
+        &fixture("basic.csv"),
+        "--metadata",
+        metadata_path.to_str().unwrap(),
+        "--timezone",
+        "Mars/Olympus_Mons",
+    ]);
+
+    assert_that!(output.status.success()).is_false();
+}
+
+#[test]
+fn it_should_not_produce_any_output_on_a_fatal_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_path = dir.path().join("out.csv");
+
+    let output = run(&[
+        &fixture("malformed.csv"),
+        "-o",
+        output_path.to_str().unwrap(),
+    ]);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(output_path.exists()).is_false();
+    assert_that!(String::from_utf8_lossy(&output.stderr).into_owned())
+        .contains("failed to parse transactions from csv file");
+
+    let output = run(&[&fixture("malformed.csv")]);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(output.stdout).is_empty();
+}
+
+#[test]
+fn it_should_abort_once_the_max_errors_threshold_is_reached() {
+    let output = run(&[&fixture("rejections.csv"), "--max-errors", "3"]);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(output.stdout).is_empty();
+    assert_that!(String::from_utf8_lossy(&output.stderr).into_owned())
+        .contains("aborting after 3 rejected transactions");
+
+    let output = run(&[&fixture("rejections.csv"), "--max-errors", "4"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned())
+        .contains("2,5.0000,0.0000,5.0000,false");
+}
+
+#[test]
+fn it_should_only_process_the_sampled_transactions() {
+    let output = run(&[&fixture("basic.csv"), "--sample", "2"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "client,available,held,total,locked\n1,7.5000,0.0000,7.5000,false\n".to_string(),
+    );
+}
+
+#[test]
+fn it_should_succeed_with_no_clients_for_empty_and_header_only_files() {
+    for name in ["empty.csv", "header_only.csv"] {
+        let output = run(&[&fixture(name)]);
+
+        assert_that!(output.status.success()).is_true();
+        assert_that!(output.stdout).is_empty();
+    }
+}
+
+#[test]
+fn it_should_behave_like_the_default_invocation_with_the_process_subcommand() {
+    let default = run(&[&fixture("basic.csv")]);
+    let process = run(&["process", &fixture("basic.csv")]);
+
+    assert_that!(process.status.success()).is_true();
+    assert_that!(process.stdout).is_equal_to(default.stdout);
+}
+
+#[test]
+fn it_should_report_rejected_transactions_with_the_validate_subcommand() {
+    let output = run(&["validate", &fixture("rejections.csv")]);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(output.stdout).is_empty();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert_that!(stderr).contains("rejected transaction with id: \"2\"");
+    assert_that!(stderr).contains("rejected transaction with id: \"99\"");
+    assert_that!(stderr).contains("3 transactions were rejected");
+
+    let output = run(&["validate", &fixture("basic.csv")]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(output.stdout).is_empty();
+}
+
+#[test]
+fn it_should_print_aggregate_totals_with_the_stats_subcommand() {
+    let output = run(&["stats", &fixture("basic.csv")]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "clients,locked,available,held,total\n2,0,7.5000,5.0000,12.5000\n".to_string(),
+    );
+}
+
+#[test]
+fn it_should_process_transactions_in_the_order_of_the_order_by_column() {
+    let output = run(&[&fixture("shuffled.csv"), "--order-by", "seq"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "client,available,held,total,locked\n\
+         1,1.0000,0.0000,1.0000,false\n\
+         2,0.0000,0.0000,0.0000,true\n"
+            .to_string(),
+    );
+}
+
+#[test]
+fn it_should_process_every_csv_file_in_the_input_dir_in_name_order() {
+    let output = run(&["--input-dir", &fixture("daily")]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "client,available,held,total,locked\n\
+         1,0.0000,0.0000,0.0000,true\n\
+         2,3.5000,0.0000,3.5000,false\n"
+            .to_string(),
+    );
+}
+
+#[test]
+fn it_should_skip_transactions_repeated_across_files_with_dedupe_across_files() {
+    let args = ["--input-dir", &fixture("overlapping"), "--max-errors", "1"];
+    let output = run(&args);
+
+    assert_that!(output.status.success()).is_false();
+
+    let output = run(&[&args[..], &["--dedupe-across-files"]].concat());
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "client,available,held,total,locked\n\
+         1,7.5000,0.0000,7.5000,false\n\
+         2,5.0000,0.0000,5.0000,false\n"
+            .to_string(),
+    );
+}
+
+#[test]
+fn it_should_write_finalized_clients_first_with_stream_output() {
+    let output = run(&[&fixture("finalized.csv"), "--stream-output"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "client,available,held,total,locked\n\
+         2,10.0000,0.0000,10.0000,false\n\
+         1,3.0000,0.0000,3.0000,false\n"
+            .to_string(),
+    );
+}
+
+#[test]
+fn it_should_trace_the_explained_transaction_to_stderr() {
+    let output = run(&[&fixture("basic.csv"), "--explain", "3"]);
+
+    assert_that!(output.status.success()).is_true();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    // Both the deposit and the dispute that references it share the tx id.
+    assert_that!(stderr).contains("explain deposit transaction: \"3\" for client: 2");
+    assert_that!(stderr).contains("explain dispute transaction: \"3\" for client: 2");
+    assert_that!(stderr).contains("tx state: deposit -> dispute");
+    assert_that!(stderr).contains("result: applied");
+    assert_that!(stderr.lines().count()).is_equal_to(10);
+}
+
+#[test]
+fn it_should_parse_input_with_a_custom_quote_character() {
+    let output = run(&[&fixture("single_quoted.csv"), "--quote", "'"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned()).is_equal_to(
+        "client,available,held,total,locked\n\
+         1,7.5000,0.0000,7.5000,false\n\
+         2,5.0000,0.0000,5.0000,false\n"
+            .to_string(),
+    );
+
+    let output = run(&[&fixture("single_quoted.csv")]);
+
+    assert_that!(output.status.success()).is_false();
+}
+
+#[test]
+fn it_should_read_columns_by_position_with_no_headers() {
+    let with_headers = run(&[&fixture("basic.csv")]);
+    let output = run(&[&fixture("no_headers.csv"), "--no-headers"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(output.stdout).is_equal_to(with_headers.stdout);
+}
+
+#[test]
+fn it_should_only_write_the_selected_columns() {
+    let output = run(&[&fixture("basic.csv"), "--columns", "client,total"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned())
+        .is_equal_to("client,total\n1,7.5000\n2,5.0000\n".to_string());
+
+    let output = run(&[&fixture("basic.csv"), "--columns", "client,balance"]);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(String::from_utf8_lossy(&output.stderr).into_owned())
+        .contains("unknown column: \"balance\"");
+}
+
+#[test]
+fn it_should_process_ndjson_input_like_the_equivalent_csv() {
+    let csv = run(&[&fixture("basic.csv")]);
+    let ndjson = run(&[&fixture("basic.ndjson"), "--input-format", "ndjson"]);
+
+    assert_that!(ndjson.status.success()).is_true();
+    assert_that!(ndjson.stdout).is_equal_to(csv.stdout);
+}
+
+#[test]
+fn it_should_report_rejected_transactions_as_json() {
+    let args = [
+        &fixture("rejections.csv"),
+        "--max-errors",
+        "1",
+        "--error-format",
+        "json",
+    ];
+    let output = run(&args);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(output.stdout).is_empty();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let rejection: serde_json::Value =
+        serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+    assert_that!(rejection["tx"]).is_equal_to(serde_json::json!(2));
+    assert_that!(rejection["client"]).is_equal_to(serde_json::json!(1));
+    assert_that!(rejection["type"]).is_equal_to(serde_json::json!("insufficient funds"));
+    assert_that!(rejection["message"].as_str().unwrap()).contains("insufficient funds");
+}
+
+#[test]
+fn it_should_abort_when_an_overdraft_would_leave_a_negative_total_in_strict_balance_mode() {
+    let output = run(&[&fixture("overdraft.csv"), "--overdraft-limit", "5"]);
+
+    assert_that!(output.status.success()).is_true();
+    assert_that!(String::from_utf8_lossy(&output.stdout).into_owned())
+        .contains("1,-5.0000,0.0000,-5.0000,false");
+
+    let args = [
+        &fixture("overdraft.csv"),
+        "--overdraft-limit",
+        "5",
+        "--strict-balance",
+    ];
+    let output = run(&args);
+
+    assert_that!(output.status.success()).is_false();
+    assert_that!(output.stdout).is_empty();
+    assert_that!(String::from_utf8_lossy(&output.stderr).into_owned())
+        .contains("transaction: \"2\" would leave the total of client: 1 below zero");
+}
+
+#[test]
+fn it_should_only_write_json_to_stderr_with_the_json_error_format() {
+    let args = [
+        &fixture("rejections.csv"),
+        "--max-errors",
+        "1",
+        "--error-format",
+        "json",
+    ];
+    let aborted = run(&args);
+    let validated = run(&[
+        "validate",
+        &fixture("rejections.csv"),
+        "--error-format",
+        "json",
+    ]);
+
+    for (output, aborted, rejected) in [(aborted, true, 1), (validated, false, 3)] {
+        assert_that!(output.status.success()).is_false();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let lines: Vec<serde_json::Value> = stderr
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_that!(lines).has_length(rejected + 1);
+        let summary = lines.last().unwrap();
+        assert_that!(summary["aborted"]).is_equal_to(serde_json::json!(aborted));
+        assert_that!(summary["rejected"]).is_equal_to(serde_json::json!(rejected));
+    }
+}